@@ -0,0 +1,86 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::hint::black_box;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
+
+use tomoru::AppState;
+
+// Distinct client IPs in the simulated traffic
+const DISTINCT_IPS: u32 = 10_000;
+
+// Requests sent by each thread per iteration
+const REQUESTS_PER_THREAD: u64 = 10_000;
+
+// Deterministic client IPs where a few hot addresses make most requests,
+// as behind a busy service with a long tail of occasional clients
+fn traffic(seed: u64, len: u64) -> Vec<IpAddr> {
+    let mut state = seed | 1;
+    (0..len)
+        .map(|_| {
+            // xorshift64
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let uniform = state as f64 / u64::MAX as f64;
+            let index = (uniform.powi(4) * DISTINCT_IPS as f64) as u32;
+            IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + index))
+        })
+        .collect()
+}
+
+fn increment_ip_count(c: &mut Criterion) {
+    let mut group = c.benchmark_group("increment_ip_count");
+    for threads in [1, 4, 8] {
+        let traffic: Vec<Vec<IpAddr>> = (0..threads)
+            .map(|thread| traffic(thread + 1, REQUESTS_PER_THREAD))
+            .collect();
+        group.throughput(Throughput::Elements(threads * REQUESTS_PER_THREAD));
+        group.bench_with_input(
+            BenchmarkId::new("threads", threads),
+            &traffic,
+            |b, traffic| {
+                b.iter_custom(|iters| {
+                    let state = AppState::default();
+                    let mut elapsed = Duration::ZERO;
+                    for _ in 0..iters {
+                        let start = Instant::now();
+                        std::thread::scope(|scope| {
+                            for ips in traffic {
+                                let state = &state;
+                                scope.spawn(move || {
+                                    for &ip in ips {
+                                        black_box(state.increment_ip_count(ip));
+                                    }
+                                });
+                            }
+                        });
+                        elapsed += start.elapsed();
+                    }
+                    elapsed
+                });
+            },
+        );
+    }
+    group.finish();
+}
+
+fn get_sorted_ip_counts(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get_sorted_ip_counts");
+    for ips in [1_000, 10_000, 100_000] {
+        let state = AppState::default();
+        for index in 0..ips {
+            let ip = IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + index));
+            for _ in 0..index % 7 + 1 {
+                state.increment_ip_count(ip);
+            }
+        }
+        group.throughput(Throughput::Elements(u64::from(ips)));
+        group.bench_with_input(BenchmarkId::new("ips", ips), &state, |b, state| {
+            b.iter(|| black_box(state.get_sorted_ip_counts()));
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, increment_ip_count, get_sorted_ip_counts);
+criterion_main!(benches);
@@ -0,0 +1,8519 @@
+//! Counting of HTTP requests per client IP, the endpoints serving the stats
+//! and the server running them, started by the binary with `run`
+
+use anyhow::{bail, Context, Result};
+use axum::{
+    body::{Body, HttpBody},
+    extract::ws::{Message, WebSocket, WebSocketUpgrade},
+    extract::ConnectInfo,
+    extract::{FromRequestParts, MatchedPath, Path as UrlPath, Query, Request, State},
+    http::{header, request::Parts, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::{from_fn, from_fn_with_state, map_response, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{get, post},
+    Json, Router,
+};
+use axum_server::{
+    accept::Accept,
+    tls_rustls::{RustlsAcceptor, RustlsConfig},
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use clap::{Parser, ValueEnum};
+use dashmap::{DashMap, DashSet};
+use futures_util::stream::{self, Stream};
+use hdrhistogram::Histogram;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::{
+    borrow::Borrow,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    future::Ready,
+    hash::{BuildHasher, Hash, RandomState},
+    io::{self, IsTerminal},
+    net::SocketAddr,
+    num::NonZeroUsize,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    sync::{Arc, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError},
+    task::{self, Poll},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::{TcpStream, UdpSocket},
+    signal,
+    task::JoinHandle,
+    time,
+};
+use tokio_util::sync::CancellationToken;
+use tower::limit::GlobalConcurrencyLimitLayer;
+use tower_http::{
+    catch_panic::CatchPanicLayer,
+    compression::CompressionLayer,
+    cors::{AllowOrigin, CorsLayer},
+    limit::RequestBodyLimitLayer,
+    timeout::TimeoutLayer,
+};
+use tracing::{error, info, warn};
+use tracing_subscriber::{fmt::MakeWriter, util::SubscriberInitExt, EnvFilter};
+
+/// Command line options
+///
+/// Some of them can also be set through TOMORU_* environment variables,
+/// with flags taking precedence over the environment
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about = "Simple service that counts HTTP requests per IP address"
+)]
+struct Args {
+    /// Address to listen on (can be repeated or comma-separated to listen on several)
+    #[arg(
+        long,
+        env = "TOMORU_BIND",
+        value_delimiter = ',',
+        default_value = "0.0.0.0:3000"
+    )]
+    bind: Vec<String>,
+
+    /// Serve the stats and admin endpoints only on this separate address,
+    /// leaving just /ping on the --bind ones
+    #[arg(long, value_name = "ADDR")]
+    admin_bind: Option<SocketAddr>,
+
+    /// Try the ports START-END in turn on each --bind IP until one is free
+    #[arg(long, value_name = "START-END", value_parser = parse_port_range, conflicts_with = "uds")]
+    port_range: Option<RangeInclusive<u16>>,
+
+    /// Take the client IP from the X-Forwarded-For header (only enable behind a trusted proxy)
+    #[arg(long)]
+    trust_proxy: bool,
+
+    /// Take the client IP from this header, e.g. CF-Connecting-IP (only set behind a trusted proxy)
+    #[arg(long, value_name = "NAME")]
+    real_ip_header: Option<HeaderName>,
+
+    /// Count requests repeating this header's value from the same IP within
+    /// --dedup-ttl only once, e.g. Idempotency-Key for retrying clients
+    #[arg(long, value_name = "NAME")]
+    dedup_header: Option<HeaderName>,
+
+    /// Seconds a --dedup-header value is remembered for
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value_t = 60,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    dedup_ttl: u64,
+
+    /// JSON file to persist IP counts to, restored on startup
+    #[arg(long, env = "TOMORU_STATE_FILE")]
+    state_file: Option<PathBuf>,
+
+    /// Seconds between saves of the state file (or reloads of the --replica file)
+    #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    persist_interval: u64,
+
+    /// Serve read-only stats from the state file of a primary instance,
+    /// reloaded every --persist-interval seconds, without counting own requests
+    #[arg(long, value_name = "STATE_FILE", conflicts_with = "state_file")]
+    replica: Option<PathBuf>,
+
+    /// Seconds between periodic stats prints (0 disables printing)
+    #[arg(
+        long,
+        env = "TOMORU_STATS_INTERVAL",
+        default_value_t = 1,
+        allow_negative_numbers = true
+    )]
+    stats_interval: u64,
+
+    /// Enable the POST /reset endpoint that clears all counts,
+    /// and POST /stats/reset-ip/{addr} that clears a single IP's
+    #[arg(long)]
+    enable_reset: bool,
+
+    /// Maximum requests per IP within --rate-window before responding with 429
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    rate_limit: Option<u64>,
+
+    /// Length of the rate limiting window in seconds
+    #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    rate_window: u64,
+
+    /// Ban IPs with more than this many requests, responding with 403
+    #[arg(long)]
+    ban_threshold: Option<u64>,
+
+    /// Respond with 403 to the IPs and CIDR networks listed in this file,
+    /// one per line, reloading it on SIGHUP
+    #[arg(long, value_name = "PATH")]
+    blocklist: Option<PathBuf>,
+
+    /// Log a warning once for each IP going over this many requests
+    #[arg(long)]
+    alert_threshold: Option<u64>,
+
+    /// PEM certificate chain to serve HTTPS with (requires --tls-key)
+    #[arg(long)]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key to serve HTTPS with (requires --tls-cert)
+    #[arg(long)]
+    tls_key: Option<PathBuf>,
+
+    /// Also accept HTTP/2 over plain HTTP (h2c with prior knowledge);
+    /// over TLS it's always negotiated via ALPN
+    #[arg(long)]
+    http2: bool,
+
+    /// Maximum number of tracked IPs, evicting the lowest count to make room
+    #[arg(long)]
+    max_ips: Option<NonZeroUsize>,
+
+    /// Preallocate room for this many IPs to avoid rehashing as they arrive,
+    /// at the cost of reserving that memory up front
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(..=MAX_EXPECTED_IPS)
+    )]
+    expected_ips: Option<usize>,
+
+    /// Listen on this Unix domain socket instead of the TCP --bind address
+    #[arg(long, conflicts_with_all = ["tls_cert", "tls_key"])]
+    uds: Option<PathBuf>,
+
+    /// Log a structured event for every request (verbose under high traffic)
+    #[arg(long)]
+    access_log: bool,
+
+    /// Serve requests from this IP without counting them (can be repeated)
+    #[arg(long = "ignore-ip", value_name = "IP")]
+    ignore_ips: Vec<IpAddr>,
+
+    /// Zero the last octet of IPv4 and the last 80 bits of IPv6 client IPs
+    /// before counting or logging them, e.g. 1.2.3.4 as 1.2.3.0
+    #[arg(long)]
+    anonymize: bool,
+
+    /// Only count requests whose path starts with this prefix (can be repeated),
+    /// serving the rest uncounted
+    #[arg(long = "count-path-prefix", value_name = "PREFIX")]
+    count_path_prefixes: Vec<String>,
+
+    /// Body returned by the /ping endpoint
+    #[arg(long, default_value = "pong")]
+    ping_response: String,
+
+    /// Don't print statistics periodically (they are still served on demand)
+    #[arg(long)]
+    quiet: bool,
+
+    /// Maximum number of requests handled at once, queueing the rest (unlimited by default)
+    #[arg(long)]
+    max_connections: Option<NonZeroUsize>,
+
+    /// Maximum open TCP connections per peer IP, closing excess ones right away
+    /// (unlimited by default, all connections come from the proxy behind one)
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), conflicts_with = "uds")]
+    max_conns_per_ip: Option<u64>,
+
+    /// Set TCP_NODELAY on accepted connections, sending small responses right away
+    #[arg(long, conflicts_with = "uds")]
+    tcp_nodelay: bool,
+
+    /// Enable TCP keepalive on accepted connections, probing after this many idle seconds
+    #[arg(
+        long,
+        value_name = "SECS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with = "uds"
+    )]
+    tcp_keepalive: Option<u64>,
+
+    /// Respond with 408 to requests not handled within this many seconds (no timeout by default)
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    request_timeout: Option<u64>,
+
+    /// Respond with 413 to requests with bodies larger than this many bytes
+    #[arg(long, value_name = "N", default_value_t = 64 * 1024)]
+    max_body_bytes: usize,
+
+    /// MaxMind DB with country data (e.g. GeoLite2-Country.mmdb) to count requests per country
+    #[arg(long, value_name = "PATH")]
+    geoip_db: Option<PathBuf>,
+
+    /// Append the periodic statistics to this file instead of logging them
+    #[arg(long, value_name = "PATH")]
+    stats_file: Option<PathBuf>,
+
+    /// Write a timestamped snapshot of the IP counts in the --state-file format
+    /// to this directory every stats tick, creating it if needed
+    #[arg(long, value_name = "DIR")]
+    snapshot_dir: Option<PathBuf>,
+
+    /// Number of the newest snapshots kept in --snapshot-dir, deleting older ones
+    #[arg(long, value_name = "N", default_value = "24")]
+    snapshot_keep: NonZeroUsize,
+
+    /// Send the total requests, unique IPs and top IP's count as StatsD gauges
+    /// over UDP to this HOST:PORT on every stats tick (not with --quiet)
+    #[arg(long, value_name = "HOST:PORT")]
+    statsd: Option<String>,
+
+    /// Show the PTR hostnames of the busiest IPs in the text stats,
+    /// looked up in the background
+    #[arg(long)]
+    resolve_ptr: bool,
+
+    /// Shut down gracefully after this many seconds and print the final IP counts,
+    /// e.g. to sample a load test
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    run_for: Option<u64>,
+
+    /// Validate the configuration and exit without binding or starting anything
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Multiply every IP count by this factor (between 0 and 1) on each stats tick,
+    /// dropping IPs whose count reaches 0
+    #[arg(long, value_parser = parse_decay)]
+    decay: Option<f64>,
+
+    /// Allow browsers on this origin to read the stats endpoints via CORS
+    /// (can be repeated, or * for any origin)
+    #[arg(long, value_name = "ORIGIN")]
+    cors_origin: Vec<HeaderValue>,
+
+    /// Keep per-minute request totals for this many minutes, advanced on each stats tick
+    #[arg(
+        long,
+        value_name = "MINUTES",
+        default_value_t = DEFAULT_HISTORY_MINUTES,
+        value_parser = clap::value_parser!(u64).range(1..=MAX_HISTORY_MINUTES)
+    )]
+    history_minutes: u64,
+
+    /// Enable POST /shutdown for requests with an "Authorization: Bearer <TOKEN>" header
+    #[arg(
+        long,
+        env = "TOMORU_ADMIN_TOKEN",
+        value_name = "TOKEN",
+        hide_env_values = true
+    )]
+    admin_token: Option<String>,
+
+    /// Require HTTP Basic auth with this user name on the stats endpoints
+    #[arg(long, requires = "stats_pass")]
+    stats_user: Option<String>,
+
+    /// Password for --stats-user
+    #[arg(
+        long,
+        env = "TOMORU_STATS_PASS",
+        hide_env_values = true,
+        requires = "stats_user"
+    )]
+    stats_pass: Option<String>,
+
+    /// Heading of the IP counts in the text stats, e.g. to tell services apart in shared logs
+    #[arg(long, value_name = "STRING", default_value = "IPs")]
+    stats_label: String,
+
+    /// Show each IP's share of all requests next to its count in the text stats
+    #[arg(long)]
+    stats_percent: bool,
+
+    /// Only list the N busiest IPs in the printed stats, summing up the rest on an "other" line
+    #[arg(long, value_name = "N")]
+    stats_top: Option<usize>,
+
+    /// Highlight the busiest IPs in the printed stats in red and yellow
+    /// (auto: only when writing them to a terminal)
+    #[arg(long, value_enum, default_value_t = ColorMode::Auto)]
+    color: ColorMode,
+
+    /// Format of the log output, including the periodic stats
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+}
+
+// Format of the log output
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum LogFormat {
+    // Human-readable lines
+    Text,
+    // One JSON object per line, for log shippers
+    Json,
+}
+
+const DEFAULT_HISTORY_MINUTES: u64 = 60;
+
+// Upper bound of --expected-ips, well below where the preallocation would overflow
+const MAX_EXPECTED_IPS: u64 = 100_000_000;
+
+// At most a week of per-minute totals is kept
+const MAX_HISTORY_MINUTES: u64 = 7 * 24 * 60;
+
+// When to color the printed stats
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum ColorMode {
+    // Only when stdout is a terminal
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorMode {
+    // Whether to color, given whether stdout is a terminal
+    fn enabled(self, terminal: bool) -> bool {
+        match self {
+            ColorMode::Auto => terminal,
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+        }
+    }
+}
+
+// Whether the printed stats end up in a terminal that should show them in color
+// Stats appended to --stats-file or logged as JSON are never colored
+fn use_color(args: &Args) -> bool {
+    args.stats_file.is_none()
+        && args.log_format == LogFormat::Text
+        && args.color.enabled(io::stdout().is_terminal())
+}
+
+// Configuration loaded from what the arguments refer to
+struct Config {
+    bind_addrs: Vec<SocketAddr>,
+    tls_config: Option<RustlsConfig>,
+    geoip: Option<maxminddb::Reader<Vec<u8>>>,
+    blocklist: Blocklist,
+    // Resolved StatsD server, connected to only once the server starts
+    statsd: Option<SocketAddr>,
+}
+
+// Single IP entry in the JSON statistics
+#[derive(Serialize, Debug, PartialEq)]
+struct IpCount {
+    ip: IpAddr,
+    count: u64,
+}
+
+// Request count of an IP with when it was first and last seen,
+// as Unix timestamps in seconds, in the detailed JSON statistics
+#[derive(Serialize, Debug, PartialEq)]
+struct IpDetails {
+    ip: IpAddr,
+    count: u64,
+    first_seen: u64,
+    last_seen: u64,
+}
+
+// Bytes received from and sent to an IP in the JSON statistics
+#[derive(Serialize, Debug, PartialEq)]
+struct IpBandwidth {
+    ip: IpAddr,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+// Body of every error response, e.g. {"error": "Not found", "code": 404}
+#[derive(Serialize, Debug, PartialEq)]
+struct ErrorBody {
+    error: &'static str,
+    code: u16,
+}
+
+// Error responses of the endpoints and middleware, rendered as an ErrorBody
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ApiError {
+    BadRequest(&'static str),
+    Unauthorized,
+    Forbidden,
+    NotFound(&'static str),
+    MethodNotAllowed,
+    RequestTimeout,
+    PayloadTooLarge,
+    TooManyRequests,
+    Internal,
+}
+
+impl ApiError {
+    fn status(self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ApiError::RequestTimeout => StatusCode::REQUEST_TIMEOUT,
+            ApiError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn message(self) -> &'static str {
+        match self {
+            ApiError::BadRequest(message) | ApiError::NotFound(message) => message,
+            ApiError::Unauthorized => "Unauthorized",
+            ApiError::Forbidden => "Forbidden",
+            ApiError::MethodNotAllowed => "Method not allowed",
+            ApiError::RequestTimeout => "Request timeout",
+            ApiError::PayloadTooLarge => "Payload too large",
+            ApiError::TooManyRequests => "Too many requests",
+            ApiError::Internal => "Internal server error",
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        let body = ErrorBody {
+            error: self.message(),
+            code: status.as_u16(),
+        };
+        (status, Json(body)).into_response()
+    }
+}
+
+// Query extractor rejecting unparsable parameters with an ApiError
+struct ApiQuery<T>(T);
+
+impl<T: DeserializeOwned, S: Send + Sync> FromRequestParts<S> for ApiQuery<T> {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Query(params) = Query::from_request_parts(parts, state)
+            .await
+            .map_err(|_| ApiError::BadRequest("Invalid query parameters"))?;
+        Ok(ApiQuery(params))
+    }
+}
+
+// Path extractor rejecting undecodable segments with an ApiError
+struct ApiPath<T>(T);
+
+impl<T: DeserializeOwned + Send, S: Send + Sync> FromRequestParts<S> for ApiPath<T> {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let UrlPath(params) = UrlPath::from_request_parts(parts, state)
+            .await
+            .map_err(|_| ApiError::BadRequest("Invalid path"))?;
+        Ok(ApiPath(params))
+    }
+}
+
+/// Replaces the plain text bodies of errors answered by the routing and the
+/// timeout and body limit layers with the JSON of the matching ApiError
+async fn json_errors(response: Response) -> Response {
+    let error = match response.status() {
+        StatusCode::METHOD_NOT_ALLOWED => ApiError::MethodNotAllowed,
+        StatusCode::REQUEST_TIMEOUT => ApiError::RequestTimeout,
+        StatusCode::PAYLOAD_TOO_LARGE => ApiError::PayloadTooLarge,
+        _ => return response,
+    };
+    // Keep the other headers, like the Allow of a 405
+    let (mut parts, _) = response.into_parts();
+    let (error_parts, body) = error.into_response().into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.extend(error_parts.headers);
+    Response::from_parts(parts, body)
+}
+
+// Build information of the running binary
+#[derive(Serialize, Debug, PartialEq)]
+struct VersionInfo {
+    version: &'static str,
+    // Missing when built outside a git checkout
+    git_commit: Option<&'static str>,
+}
+
+// Headline numbers of the JSON summary
+#[derive(Serialize, Debug, PartialEq)]
+struct Summary {
+    total_requests: u64,
+    unique_ips: usize,
+    ipv4_requests: u64,
+    ipv6_requests: u64,
+    top_ip: Option<IpCount>,
+}
+
+// Request count of a network prefix in the JSON statistics
+#[derive(Serialize, Debug, PartialEq)]
+struct SubnetCount {
+    subnet: String,
+    count: u64,
+}
+
+// On-disk format of the state file, ordered by IP for stable output
+type PersistedCounts = BTreeMap<IpAddr, u64>;
+
+// Number of shards in a ShardedCounter
+const SHARD_COUNT: usize = 16;
+
+// A full ShardedCounter evicts this fraction of its max_keys at once
+const EVICTION_BATCH_DIVISOR: usize = 16;
+
+// Counter of a single key along with when it was first and last incremented
+struct CounterEntry {
+    count: AtomicU64,
+    first_seen: Instant,
+    // Nanoseconds since the owning counter's epoch, kept atomic
+    // so hits can update it under a shared shard lock
+    last_seen: AtomicU64,
+}
+
+impl CounterEntry {
+    // Add to the count and move last_seen forward, returning the new count
+    fn hit(&self, amount: u64, last_seen: u64) -> u64 {
+        // Concurrent hits may finish out of order, so keep the latest one
+        self.last_seen.fetch_max(last_seen, Ordering::Relaxed);
+        self.count.fetch_add(amount, Ordering::Relaxed) + amount
+    }
+}
+
+// Point-in-time copy of a CounterEntry
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct CounterDetails {
+    count: u64,
+    first_seen: Instant,
+    last_seen: Instant,
+}
+
+type Shard<K> = HashMap<K, CounterEntry>;
+
+// Map of counters split into independently locked shards selected by key hash,
+// so concurrent increments of different keys rarely contend on the same lock
+struct ShardedCounter<K> {
+    hasher: RandomState,
+    shards: [RwLock<Shard<K>>; SHARD_COUNT],
+    // Reference point for the entries' last_seen offsets
+    epoch: Instant,
+    // Upper bound for the number of keys, if any
+    max_keys: Option<NonZeroUsize>,
+    // Serializes insertions of new keys while max_keys is enforced
+    insert_lock: Mutex<()>,
+}
+
+impl<K> Default for ShardedCounter<K> {
+    fn default() -> Self {
+        Self::with_max_keys(None)
+    }
+}
+
+impl<K> ShardedCounter<K> {
+    fn with_max_keys(max_keys: Option<NonZeroUsize>) -> Self {
+        Self::with_capacity(0).limited_to(max_keys)
+    }
+
+    // Preallocate room for about `capacity` keys, spread over the shards
+    fn with_capacity(capacity: usize) -> Self {
+        let shard_capacity = capacity.div_ceil(SHARD_COUNT);
+        Self {
+            hasher: RandomState::new(),
+            shards: std::array::from_fn(|_| RwLock::new(HashMap::with_capacity(shard_capacity))),
+            epoch: Instant::now(),
+            max_keys: None,
+            insert_lock: Mutex::new(()),
+        }
+    }
+
+    fn limited_to(mut self, max_keys: Option<NonZeroUsize>) -> Self {
+        self.max_keys = max_keys;
+        self
+    }
+}
+
+impl<K: Clone + Eq + Hash> ShardedCounter<K> {
+    // Index of the shard holding the key
+    fn shard_index<Q: Hash + ?Sized>(&self, key: &Q) -> usize {
+        (self.hasher.hash_one(key) % SHARD_COUNT as u64) as usize
+    }
+
+    // Counters stay consistent even if a thread panicked while holding a shard
+    // lock, since every update is a single atomic operation, so poisoning is ignored
+    fn read_shard(&self, index: usize) -> RwLockReadGuard<'_, Shard<K>> {
+        self.shards[index]
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write_shard(&self, index: usize) -> RwLockWriteGuard<'_, Shard<K>> {
+        self.shards[index]
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Add to the key's counter, returning the new count
+    fn add<Q>(&self, key: &Q, amount: u64) -> u64
+    where
+        K: Borrow<Q>,
+        Q: ToOwned<Owned = K> + Eq + Hash + ?Sized,
+    {
+        self.add_at(key, amount, Instant::now())
+    }
+
+    // Add to the key's counter as of the given time, returning the new count
+    fn add_at<Q>(&self, key: &Q, amount: u64, now: Instant) -> u64
+    where
+        K: Borrow<Q>,
+        Q: ToOwned<Owned = K> + Eq + Hash + ?Sized,
+    {
+        self.add_at_evicting(key, amount, now, |_| {})
+    }
+
+    // Add to the key's counter as of the given time, returning the new count
+    // and passing the keys evicted to make room for it to `on_evict`
+    // Counters are independent and never used to synchronize other memory,
+    // so Relaxed ordering is enough for every access to them
+    fn add_at_evicting<Q>(&self, key: &Q, amount: u64, now: Instant, on_evict: impl FnMut(K)) -> u64
+    where
+        K: Borrow<Q>,
+        Q: ToOwned<Owned = K> + Eq + Hash + ?Sized,
+    {
+        let index = self.shard_index(key);
+        let last_seen = now.saturating_duration_since(self.epoch).as_nanos() as u64;
+
+        // Fast path: known keys only need a shared shard lock and atomic updates
+        if let Some(entry) = self.read_shard(index).get(key) {
+            return entry.hit(amount, last_seen);
+        }
+
+        if let Some(max_keys) = self.max_keys {
+            // Without serializing, concurrent new keys could all see
+            // room for themselves and overshoot the cap together
+            let _insert = self
+                .insert_lock
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+
+            let len = self.len();
+            if len >= max_keys.get() && !self.read_shard(index).contains_key(key) {
+                // Make room for the following new keys too, so the scan
+                // for the lowest counts only runs every so many of them
+                let batch = max_keys.get() / EVICTION_BATCH_DIVISOR;
+                let evicted = self.evict_lowest((len + 1 - max_keys.get()).max(batch));
+                evicted.into_iter().for_each(on_evict);
+            }
+        }
+
+        // Slow path: take the shard write lock once to insert the new key
+        self.write_shard(index)
+            .entry(key.to_owned())
+            .or_insert_with(|| CounterEntry {
+                count: AtomicU64::new(0),
+                first_seen: now,
+                last_seen: AtomicU64::new(last_seen),
+            })
+            .hit(amount, last_seen)
+    }
+
+    // Remove up to `count` keys with the lowest counts, returning them
+    // This scans every key, so it's only run for a batch of them at a time
+    fn evict_lowest(&self, count: usize) -> Vec<K> {
+        let mut counts = self.snapshot();
+        let count = count.min(counts.len());
+        if count == 0 {
+            return Vec::new();
+        }
+        counts.select_nth_unstable_by_key(count - 1, |(_, count)| *count);
+        counts.truncate(count);
+
+        counts
+            .into_iter()
+            .map(|(key, _)| {
+                self.write_shard(self.shard_index(&key)).remove(&key);
+                key
+            })
+            .collect()
+    }
+
+    // Increment the key's counter, returning the new count
+    fn increment<Q>(&self, key: &Q) -> u64
+    where
+        K: Borrow<Q>,
+        Q: ToOwned<Owned = K> + Eq + Hash + ?Sized,
+    {
+        self.add(key, 1)
+    }
+
+    // Number of tracked keys
+    fn len(&self) -> usize {
+        (0..SHARD_COUNT)
+            .map(|index| self.read_shard(index).len())
+            .sum()
+    }
+
+    // Remove all keys
+    fn clear(&self) {
+        for index in 0..SHARD_COUNT {
+            self.write_shard(index).clear();
+        }
+    }
+
+    // Remove the key, returning its count if it was tracked
+    fn remove<Q>(&self, key: &Q) -> Option<u64>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.write_shard(self.shard_index(key))
+            .remove(key)
+            .map(|entry| entry.count.into_inner())
+    }
+
+    // Current count of the key, if it's tracked
+    fn get<Q>(&self, key: &Q) -> Option<u64>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.read_shard(self.shard_index(key))
+            .get(key)
+            .map(|entry| entry.count.load(Ordering::Relaxed))
+    }
+
+    // Multiply every count by the factor, rounding down and removing keys that reach 0
+    #[cfg_attr(not(feature = "stats-printer"), allow(dead_code))]
+    fn scale(&self, factor: f64) {
+        for index in 0..SHARD_COUNT {
+            self.write_shard(index).retain(|_, entry| {
+                let count = entry.count.get_mut();
+                *count = (*count as f64 * factor) as u64;
+                *count > 0
+            });
+        }
+    }
+
+    // Sum of all counts, without collecting them
+    fn total(&self) -> u64 {
+        (0..SHARD_COUNT)
+            .map(|index| {
+                self.read_shard(index)
+                    .values()
+                    .map(|entry| entry.count.load(Ordering::Relaxed))
+                    .sum::<u64>()
+            })
+            .sum()
+    }
+
+    // Collect all counts, unordered
+    fn snapshot(&self) -> Vec<(K, u64)> {
+        let mut counts = Vec::with_capacity(self.len());
+        for index in 0..SHARD_COUNT {
+            counts.extend(
+                self.read_shard(index)
+                    .iter()
+                    .map(|(key, entry)| (key.clone(), entry.count.load(Ordering::Relaxed))),
+            );
+        }
+        counts
+    }
+
+    // Collect all counts with their timestamps, unordered
+    fn details(&self) -> Vec<(K, CounterDetails)> {
+        let mut details = Vec::with_capacity(self.len());
+        for index in 0..SHARD_COUNT {
+            details.extend(self.read_shard(index).iter().map(|(key, entry)| {
+                let last_seen = Duration::from_nanos(entry.last_seen.load(Ordering::Relaxed));
+                let details = CounterDetails {
+                    count: entry.count.load(Ordering::Relaxed),
+                    first_seen: entry.first_seen,
+                    last_seen: self.epoch + last_seen,
+                };
+                (key.clone(), details)
+            }));
+        }
+        details
+    }
+
+    // Collect all counts sorted by descending count
+    fn sorted(&self) -> Vec<(K, u64)> {
+        // Collect and sort counts here since it (usually) runs less frequently
+        // than the increments, optimizing overall performance.
+        // Every caller formats from this single collected snapshot, so the
+        // output never mixes values from different points in time
+        let mut counts = self.snapshot();
+        counts.sort_by(|(_, a), (_, b)| b.cmp(a));
+        counts
+    }
+}
+
+// Limiter of the open connections per IP
+pub(crate) struct ConnectionLimiter {
+    limit: u64,
+    active: DashMap<IpAddr, u64>,
+}
+
+impl ConnectionLimiter {
+    fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            active: DashMap::new(),
+        }
+    }
+
+    // Open a connection from the IP, unless it already has as many as allowed
+    // The connection counts as open until the returned guard is dropped
+    fn acquire(self: &Arc<Self>, ip: IpAddr) -> Option<ConnectionGuard> {
+        let mut active = self.active.entry(ip).or_insert(0);
+        if *active >= self.limit {
+            return None;
+        }
+        *active += 1;
+        Some(ConnectionGuard {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+
+    // Close a connection from the IP, forgetting IPs without any left
+    fn release(&self, ip: IpAddr) {
+        if let dashmap::Entry::Occupied(mut active) = self.active.entry(ip) {
+            *active.get_mut() -= 1;
+            if *active.get() == 0 {
+                active.remove();
+            }
+        }
+    }
+}
+
+// Open connection slot of an IP, released on drop
+struct ConnectionGuard {
+    limiter: Arc<ConnectionLimiter>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.limiter.release(self.ip);
+    }
+}
+
+// Fixed-window rate limiter allowing `limit` requests per IP in each window
+struct RateLimiter {
+    limit: u64,
+    window: Duration,
+    // Start of the current window and the requests made in it
+    windows: DashMap<IpAddr, (Instant, u64)>,
+    // When expired windows were last dropped
+    last_sweep: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(limit: u64, window: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            windows: DashMap::new(),
+            last_sweep: Mutex::new(Instant::now()),
+        }
+    }
+
+    // Record a request and check whether it fits into the IP's current window
+    fn check(&self, ip: IpAddr, now: Instant) -> bool {
+        self.sweep(now);
+        let mut entry = self.windows.entry(ip).or_insert((now, 0));
+        let (start, count) = entry.value_mut();
+
+        if now.duration_since(*start) >= self.window {
+            *start = now;
+            *count = 0;
+        }
+
+        if *count >= self.limit {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    // Drop the windows of IPs that stopped making requests, at most once per window
+    // length so the scan is amortized over all the requests in between
+    fn sweep(&self, now: Instant) {
+        {
+            // Whoever holds the lock is already sweeping or about to
+            let mut last_sweep = match self.last_sweep.try_lock() {
+                Ok(last_sweep) => last_sweep,
+                Err(TryLockError::Poisoned(e)) => e.into_inner(),
+                Err(TryLockError::WouldBlock) => return,
+            };
+            if now.saturating_duration_since(*last_sweep) < self.window {
+                return;
+            }
+            *last_sweep = now;
+        }
+        self.windows
+            .retain(|_, (start, _)| now.saturating_duration_since(*start) < self.window);
+    }
+}
+
+// Most header values remembered for deduplication, dropping the oldest beyond
+const MAX_DEDUP_KEYS: usize = 10_000;
+
+// Header values seen per IP, with when they were first seen
+#[derive(Default)]
+struct SeenKeys {
+    first_seen: HashMap<(IpAddr, HeaderValue), Instant>,
+    // The same keys, oldest first, so expired and excess ones are found quickly
+    order: VecDeque<(IpAddr, HeaderValue)>,
+}
+
+// Recognizes retries by a header value the same IP already sent within `ttl`
+struct Deduplicator {
+    header: HeaderName,
+    ttl: Duration,
+    capacity: usize,
+    seen: Mutex<SeenKeys>,
+}
+
+impl Deduplicator {
+    fn new(header: HeaderName, ttl: Duration, capacity: usize) -> Self {
+        Self {
+            header,
+            ttl,
+            capacity,
+            seen: Mutex::new(SeenKeys::default()),
+        }
+    }
+
+    // Record the request's header value and check whether it's a repeat
+    // Requests without the header are never repeats
+    fn is_repeat(&self, ip: IpAddr, headers: &HeaderMap, now: Instant) -> bool {
+        let Some(value) = headers.get(&self.header) else {
+            return false;
+        };
+        let mut seen = self.seen.lock().unwrap_or_else(PoisonError::into_inner);
+        let SeenKeys { first_seen, order } = &mut *seen;
+
+        // Keys expire in the order they were first seen
+        while let Some(oldest) = order.front() {
+            if now.duration_since(first_seen[oldest]) < self.ttl {
+                break;
+            }
+            first_seen.remove(oldest);
+            order.pop_front();
+        }
+
+        let key = (ip, value.clone());
+        if first_seen.contains_key(&key) {
+            return true;
+        }
+        if order.len() >= self.capacity {
+            if let Some(oldest) = order.pop_front() {
+                first_seen.remove(&oldest);
+            }
+        }
+        first_seen.insert(key.clone(), now);
+        order.push_back(key);
+        false
+    }
+}
+
+// Mask an address so it no longer identifies a single client,
+// keeping its /24 for IPv4 and /48 for IPv6
+fn anonymize_ip(ip: IpAddr) -> IpAddr {
+    network_prefix(ip, 24, 48)
+}
+
+// Zero all but the first `v4_bits`/`v6_bits` bits of an address
+fn network_prefix(ip: IpAddr, v4_bits: u8, v6_bits: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX
+                .checked_shl(32 - u32::from(v4_bits.min(32)))
+                .unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask))
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX
+                .checked_shl(128 - u32::from(v6_bits.min(128)))
+                .unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+        }
+    }
+}
+
+// IPs and networks whose requests are rejected
+#[derive(Default, Debug, PartialEq)]
+struct Blocklist {
+    ips: HashSet<IpAddr>,
+    // Network addresses with their prefix lengths
+    networks: Vec<(IpAddr, u8)>,
+}
+
+impl Blocklist {
+    // Parse one IP or CIDR network per line, skipping empty lines and # comments
+    fn parse(contents: &str) -> Result<Self> {
+        let mut blocklist = Self::default();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            blocklist
+                .add(line)
+                .with_context(|| format!("Invalid entry on line {}: {}", number + 1, line))?;
+        }
+        Ok(blocklist)
+    }
+
+    // Add a single IP or CIDR network
+    fn add(&mut self, entry: &str) -> Result<()> {
+        let Some((ip, bits)) = entry.split_once('/') else {
+            self.ips.insert(entry.parse()?);
+            return Ok(());
+        };
+
+        let ip: IpAddr = ip.parse()?;
+        let bits: u8 = bits.parse()?;
+        let max_bits = if ip.is_ipv4() { 32 } else { 128 };
+        if bits > max_bits {
+            bail!("Prefix length must be at most {}", max_bits);
+        }
+        self.networks.push((network_prefix(ip, bits, bits), bits));
+        Ok(())
+    }
+
+    // Number of listed IPs and networks
+    fn len(&self) -> usize {
+        self.ips.len() + self.networks.len()
+    }
+
+    // Check whether the IP is listed or inside a listed network
+    fn contains(&self, ip: IpAddr) -> bool {
+        self.ips.contains(&ip)
+            || self.networks.iter().any(|&(network, bits)| {
+                network.is_ipv4() == ip.is_ipv4() && network_prefix(ip, bits, bits) == network
+            })
+    }
+}
+
+// Number of the busiest IPs whose hostnames are looked up with --resolve-ptr
+const PTR_TOP_IPS: usize = 10;
+
+// How often new busy IPs are looked up
+const PTR_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+// Longest wait for a single PTR lookup before treating it as failed
+const PTR_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Longest hostname getnameinfo can return, including the terminating NUL
+#[cfg(unix)]
+const MAX_HOSTNAME_LEN: usize = 1025;
+
+// Look up the PTR hostname of the IP, blocking the calling thread
+#[cfg(unix)]
+fn reverse_lookup(ip: IpAddr) -> Option<String> {
+    let addr = socket2::SockAddr::from(SocketAddr::new(ip, 0));
+    let mut host = [0 as libc::c_char; MAX_HOSTNAME_LEN];
+    // SAFETY: the address and the host buffer are valid for the lengths passed along
+    let result = unsafe {
+        libc::getnameinfo(
+            addr.as_ptr().cast(),
+            addr.len(),
+            host.as_mut_ptr(),
+            MAX_HOSTNAME_LEN as libc::socklen_t,
+            std::ptr::null_mut(),
+            0,
+            libc::NI_NAMEREQD,
+        )
+    };
+    if result != 0 {
+        return None;
+    }
+    // SAFETY: getnameinfo NUL-terminates the hostname on success
+    let host = unsafe { std::ffi::CStr::from_ptr(host.as_ptr()) };
+    host.to_str().ok().map(str::to_owned)
+}
+
+#[cfg(not(unix))]
+fn reverse_lookup(_ip: IpAddr) -> Option<String> {
+    None
+}
+
+// Longest window that recent request counts are kept for
+const MAX_RECENT_WINDOW: Duration = Duration::from_secs(300);
+
+// Recent request counts of one IP in one-second buckets, oldest first
+type RecentBuckets = VecDeque<(Instant, u64)>;
+
+// Time constant of the decaying per-IP request rates: a hit's weight
+// drops to 1/e after this long, so rates follow changes within about a minute
+const RATE_TIME_CONSTANT: Duration = Duration::from_secs(10);
+
+// How often expired recent buckets and faded rates are dropped
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Rates below this many requests per second are dropped when pruning
+const MIN_TRACKED_RATE: f64 = 0.001;
+
+// Exponentially decaying request count, whose value settles at
+// rate * RATE_TIME_CONSTANT for a steady rate
+struct DecayingRate {
+    value: f64,
+    updated: Instant,
+}
+
+impl DecayingRate {
+    // Value decayed to the given time
+    fn value_at(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.value * (-elapsed / RATE_TIME_CONSTANT.as_secs_f64()).exp()
+    }
+
+    // Record a hit at the given time
+    fn hit(&mut self, now: Instant) {
+        self.value = self.value_at(now) + 1.0;
+        self.updated = self.updated.max(now);
+    }
+
+    // Approximate requests per second as of the given time
+    fn per_second(&self, now: Instant) -> f64 {
+        self.value_at(now) / RATE_TIME_CONSTANT.as_secs_f64()
+    }
+}
+
+// Total requests counted in one minute, starting at the given
+// Unix timestamp in seconds, in the JSON time series
+#[derive(Serialize, Debug, PartialEq, Clone)]
+struct MinuteCount {
+    minute: u64,
+    count: u64,
+}
+
+// Change of an IP's count between two named snapshots in the JSON statistics
+#[derive(Serialize, Debug, PartialEq)]
+struct IpDiff {
+    ip: IpAddr,
+    from: u64,
+    to: u64,
+    change: i64,
+}
+
+// Most named snapshots kept at once, so they can't grow without bound
+const MAX_NAMED_SNAPSHOTS: usize = 100;
+
+// Number of IPs whose request count is within `min..=max` in the JSON statistics,
+// without a `max` for the last bucket
+#[derive(Serialize, Debug, PartialEq)]
+struct CountBucket {
+    min: u64,
+    max: Option<u64>,
+    ips: usize,
+}
+
+// Upper bounds of the /stats/histogram buckets unless others are given
+const DEFAULT_HISTOGRAM_BOUNDS: [u64; 5] = [1, 10, 100, 1000, 10000];
+
+// Number of distinct paths an IP requested in the JSON statistics
+#[derive(Serialize, Debug, PartialEq)]
+struct IpPaths {
+    ip: IpAddr,
+    distinct_paths: usize,
+}
+
+// Request count of a single IP along with its distinct paths, as served by /stats/ip
+#[derive(Serialize, Debug, PartialEq)]
+struct IpLookup {
+    ip: IpAddr,
+    count: u64,
+    distinct_paths: usize,
+}
+
+// Most distinct paths remembered per IP, so a scanner can't grow its set without bound
+const MAX_DISTINCT_PATHS: usize = 1000;
+
+// Most distinct paths counted overall, evicting the least requested ones beyond,
+// so scanners requesting unique paths can't grow the path counts without bound
+const MAX_TRACKED_PATHS: usize = 10_000;
+
+// Current request rate of an IP in the JSON statistics
+#[derive(Serialize, Debug, PartialEq)]
+struct IpRate {
+    ip: IpAddr,
+    requests_per_second: f64,
+}
+
+// Country bucket for IPs missing from the GeoIP database, such as private ones
+const UNKNOWN_COUNTRY: &str = "unknown";
+
+// Longest latency a histogram tracks precisely, in microseconds
+// Anything slower is recorded as this value
+const MAX_LATENCY_US: u64 = 60 * 60 * 1_000_000;
+
+// Latency bucket of the requests no route matched, so arbitrary paths
+// can't each allocate a histogram of their own
+const UNMATCHED_PATH: &str = "(unmatched)";
+
+// Latency percentiles of a request path in the JSON statistics, in microseconds
+#[derive(Serialize, Debug, PartialEq)]
+struct PathLatency {
+    path: String,
+    count: u64,
+    p50_us: u64,
+    p95_us: u64,
+    p99_us: u64,
+}
+
+// Stores request statistics for the application
+// All maps are sharded internally, so concurrent requests only contend
+// when their keys land in the same shard, and none of them gets stuck
+// after a thread panicked mid-update
+#[derive(Default)]
+pub struct AppState {
+    ip_counts: ShardedCounter<IpAddr>,
+    path_counts: ShardedCounter<String>,
+    // Distinct paths requested by each IP, up to MAX_DISTINCT_PATHS
+    paths_per_ip: DashMap<IpAddr, HashSet<String>>,
+    method_counts: ShardedCounter<Method>,
+    status_counts: ShardedCounter<u16>,
+    bytes_in: ShardedCounter<IpAddr>,
+    bytes_out: ShardedCounter<IpAddr>,
+    latencies: DashMap<String, Mutex<Histogram<u64>>>,
+    geoip: Option<maxminddb::Reader<Vec<u8>>>,
+    country_counts: ShardedCounter<String>,
+    recent: DashMap<IpAddr, RecentBuckets>,
+    rates: DashMap<IpAddr, DecayingRate>,
+    trust_proxy: bool,
+    real_ip_header: Option<HeaderName>,
+    rate_limiter: Option<RateLimiter>,
+    deduplicator: Option<Deduplicator>,
+    // Shared with the TCP acceptors, which open and close the connections
+    connection_limiter: Option<Arc<ConnectionLimiter>>,
+    ban_threshold: Option<u64>,
+    banned: DashSet<IpAddr>,
+    // Replaced as a whole when the blocklist file is reloaded
+    blocklist: RwLock<Blocklist>,
+    alert_threshold: Option<u64>,
+    // IPs already warned about, so each one alerts only once
+    alerted: DashSet<IpAddr>,
+    access_log: bool,
+    ignored_ips: HashSet<IpAddr>,
+    // Whether client IPs are masked before they're stored
+    anonymize: bool,
+    // Only paths with one of these prefixes are counted, all if empty
+    count_path_prefixes: Vec<String>,
+    #[cfg_attr(not(feature = "stats-printer"), allow(dead_code))]
+    decay: Option<f64>,
+    // Serving another instance's counts, so requests aren't counted
+    replica: bool,
+    // PTR hostnames of the IPs looked up so far, None where the lookup failed
+    hostnames: DashMap<IpAddr, Option<String>>,
+    // Heading of the IP counts in the text stats, "IPs" unless set
+    stats_label: Option<String>,
+    // Whether the text stats show each IP's share of all requests
+    stats_percent: bool,
+    // Number of IPs listed in the printed stats before the rest is summed up
+    stats_top: Option<usize>,
+    // Whether the printed IP counts highlight the busiest IPs, see --color
+    color: bool,
+    // Per-minute request totals, oldest first, and the requests
+    // counted since the last stats tick
+    history: Mutex<VecDeque<MinuteCount>>,
+    // IP counts captured by the last POST /stats/mark, empty if never marked
+    baseline: Mutex<HashMap<IpAddr, u64>>,
+    // IP counts captured by POST /stats/snapshot/{name}, by name
+    named_snapshots: DashMap<String, HashMap<IpAddr, u64>>,
+    history_pending: AtomicU64,
+    #[cfg_attr(not(feature = "stats-printer"), allow(dead_code))]
+    history_minutes: u64,
+    // Requests currently being handled
+    in_flight: AtomicU64,
+    // Requests counted since the server started, unaffected by resets,
+    // eviction and decay so the Prometheus counter never goes down
+    requests_served: AtomicU64,
+    // When the server started, for the uptime metric
+    started: Option<Instant>,
+    // Cancelled on Ctrl-C/SIGTERM to stop the server, background tasks and streams
+    shutdown: CancellationToken,
+    // Set once shutdown is requested, so /ping tells load balancers to move on
+    draining: AtomicBool,
+}
+
+impl AppState {
+    // State with the per-IP maps preallocated for `capacity` IPs
+    // and the path counts limited to MAX_TRACKED_PATHS
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            ip_counts: ShardedCounter::with_capacity(capacity),
+            path_counts: ShardedCounter::with_max_keys(NonZeroUsize::new(MAX_TRACKED_PATHS)),
+            bytes_in: ShardedCounter::with_capacity(capacity),
+            bytes_out: ShardedCounter::with_capacity(capacity),
+            recent: DashMap::with_capacity(capacity),
+            rates: DashMap::with_capacity(capacity),
+            ..Default::default()
+        }
+    }
+
+    // Whether requests to the path are counted
+    fn counts_path(&self, path: &str) -> bool {
+        self.count_path_prefixes.is_empty()
+            || self
+                .count_path_prefixes
+                .iter()
+                .any(|prefix| path.starts_with(prefix.as_str()))
+    }
+
+    // Determine the client IP of a request
+    // X-Forwarded-For can be set by anyone, so it's only honored when explicitly enabled
+    fn client_ip(&self, headers: &HeaderMap, peer: SocketAddr) -> IpAddr {
+        // Malformed header values fall through to the next source
+        if let Some(name) = &self.real_ip_header {
+            if let Some(ip) = header_ip(headers, name) {
+                return ip;
+            }
+        }
+        if self.trust_proxy {
+            if let Some(ip) = forwarded_for_ip(headers) {
+                return ip;
+            }
+        }
+        peer.ip()
+    }
+
+    // Check whether a request from the IP is within the rate limit, if any
+    fn allow_request(&self, ip: IpAddr) -> bool {
+        self.rate_limiter
+            .as_ref()
+            .is_none_or(|limiter| limiter.check(ip, Instant::now()))
+    }
+
+    // Check whether the request repeats a --dedup-header value the IP sent recently
+    fn is_repeat(&self, ip: IpAddr, headers: &HeaderMap, now: Instant) -> bool {
+        self.deduplicator
+            .as_ref()
+            .is_some_and(|deduplicator| deduplicator.is_repeat(ip, headers, now))
+    }
+
+    // Check whether the IP is banned, banning it once its count goes over the threshold
+    // Bans last until the IP is reset, even if --decay brings its count back down
+    fn check_ban(&self, ip: IpAddr, count: u64) -> bool {
+        match self.ban_threshold {
+            Some(threshold) if count > threshold => {
+                self.banned.insert(ip);
+                true
+            }
+            Some(_) => self.banned.contains(&ip),
+            None => false,
+        }
+    }
+
+    // Increment IP count, returning the new count
+    pub fn increment_ip_count(&self, ip: IpAddr) -> u64 {
+        self.record_rate(ip, Instant::now());
+        self.history_pending.fetch_add(1, Ordering::Relaxed);
+        self.requests_served.fetch_add(1, Ordering::Relaxed);
+        // What's kept per IP besides its count goes along with it
+        let count = self
+            .ip_counts
+            .add_at_evicting(&ip, 1, Instant::now(), |evicted| {
+                self.paths_per_ip.remove(&evicted);
+            });
+        self.check_alert(ip, count);
+        count
+    }
+
+    // Check whether the IP is on the blocklist
+    fn is_blocked(&self, ip: IpAddr) -> bool {
+        self.blocklist
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .contains(ip)
+    }
+
+    // Swap in a new blocklist
+    fn replace_blocklist(&self, blocklist: Blocklist) {
+        *self
+            .blocklist
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = blocklist;
+    }
+
+    // Warn the first time the IP's count goes over the alert threshold, if any
+    fn check_alert(&self, ip: IpAddr, count: u64) {
+        match self.alert_threshold {
+            Some(threshold) if count > threshold && self.alerted.insert(ip) => {
+                warn!(%ip, count, threshold, "IP went over the alert threshold");
+            }
+            _ => {}
+        }
+    }
+
+    // Add a hit to the IP's decaying request rate
+    fn record_rate(&self, ip: IpAddr, now: Instant) {
+        self.rates
+            .entry(ip)
+            .or_insert(DecayingRate {
+                value: 0.0,
+                updated: now,
+            })
+            .hit(now);
+    }
+
+    // Get approximate current requests per second by IP as of the
+    // given time, sorted by descending rate
+    fn rates_at(&self, now: Instant) -> Vec<IpRate> {
+        let mut rates: Vec<IpRate> = self
+            .rates
+            .iter()
+            .map(|entry| IpRate {
+                ip: *entry.key(),
+                requests_per_second: entry.value().per_second(now),
+            })
+            .collect();
+        rates.sort_by(|a, b| b.requests_per_second.total_cmp(&a.requests_per_second));
+        rates
+    }
+
+    // Apply the configured decay to the IP counts, if any
+    #[cfg_attr(not(feature = "stats-printer"), allow(dead_code))]
+    fn decay_counts(&self) {
+        if let Some(factor) = self.decay {
+            self.ip_counts.scale(factor);
+            // Drop the paths of the IPs whose counts reached 0
+            self.paths_per_ip
+                .retain(|ip, _| self.ip_counts.get(ip).is_some());
+        }
+    }
+
+    // Move the requests counted since the last tick into the bucket of the
+    // minute containing the given Unix time, dropping buckets past retention
+    #[cfg_attr(not(feature = "stats-printer"), allow(dead_code))]
+    fn advance_history(&self, unix_seconds: u64) {
+        let minute = unix_seconds - unix_seconds % 60;
+        let count = self.history_pending.swap(0, Ordering::Relaxed);
+        let mut history = self.history.lock().unwrap_or_else(PoisonError::into_inner);
+
+        match history.back_mut() {
+            Some(last) if last.minute == minute => last.count += count,
+            _ => history.push_back(MinuteCount { minute, count }),
+        }
+        while history.front().is_some_and(|oldest| {
+            oldest
+                .minute
+                .saturating_add(self.history_minutes.saturating_mul(60))
+                <= minute
+        }) {
+            history.pop_front();
+        }
+    }
+
+    // Get the per-minute request totals, oldest first
+    fn get_timeseries(&self) -> Vec<MinuteCount> {
+        let history = self.history.lock().unwrap_or_else(PoisonError::into_inner);
+        history.iter().cloned().collect()
+    }
+
+    // Drop rates that have decayed to practically nothing
+    fn prune_rates(&self, now: Instant) {
+        self.rates
+            .retain(|_, rate| rate.per_second(now) >= MIN_TRACKED_RATE);
+    }
+
+    // Increment response status code count
+    fn increment_status_count(&self, status: StatusCode) {
+        self.status_counts.increment(&status.as_u16());
+    }
+
+    // Add request and response body sizes to the IP's byte counts
+    fn record_bandwidth(&self, ip: IpAddr, bytes_in: u64, bytes_out: u64) {
+        // Skip empty bodies so bodiless requests don't create entries
+        if bytes_in > 0 {
+            self.bytes_in.add(&ip, bytes_in);
+        }
+        if bytes_out > 0 {
+            self.bytes_out.add(&ip, bytes_out);
+        }
+    }
+
+    // Get byte counts per IP sorted by descending bytes sent
+    fn get_bandwidth(&self) -> Vec<IpBandwidth> {
+        let mut bandwidth: HashMap<IpAddr, IpBandwidth> = HashMap::new();
+        for (ip, bytes_in) in self.bytes_in.snapshot() {
+            bandwidth.insert(
+                ip,
+                IpBandwidth {
+                    ip,
+                    bytes_in,
+                    bytes_out: 0,
+                },
+            );
+        }
+        for (ip, bytes_out) in self.bytes_out.snapshot() {
+            bandwidth
+                .entry(ip)
+                .or_insert(IpBandwidth {
+                    ip,
+                    bytes_in: 0,
+                    bytes_out: 0,
+                })
+                .bytes_out = bytes_out;
+        }
+
+        let mut bandwidth: Vec<_> = bandwidth.into_values().collect();
+        bandwidth.sort_by(|a, b| {
+            (b.bytes_out, b.bytes_in)
+                .cmp(&(a.bytes_out, a.bytes_in))
+                .then(a.ip.cmp(&b.ip))
+        });
+        bandwidth
+    }
+
+    // Record how long a request to the path took
+    fn record_latency(&self, path: &str, latency: Duration) {
+        let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+
+        // Known paths only need a shared map lock and their own histogram lock
+        let histogram = match self.latencies.get(path) {
+            Some(histogram) => histogram,
+            None => self
+                .latencies
+                .entry(path.to_owned())
+                .or_insert_with(|| {
+                    // 2 significant digits (1% error) keep each histogram at a few dozen KiB
+                    Mutex::new(
+                        Histogram::new_with_bounds(1, MAX_LATENCY_US, 2)
+                            .expect("Latency histogram bounds are valid"),
+                    )
+                })
+                .downgrade(),
+        };
+        histogram
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .saturating_record(micros);
+    }
+
+    // Get latency percentiles per path sorted by descending request count
+    fn get_latency_percentiles(&self) -> Vec<PathLatency> {
+        let mut latencies: Vec<PathLatency> = self
+            .latencies
+            .iter()
+            .map(|entry| {
+                let histogram = entry.value().lock().unwrap_or_else(PoisonError::into_inner);
+                PathLatency {
+                    path: entry.key().clone(),
+                    count: histogram.len(),
+                    p50_us: histogram.value_at_quantile(0.50),
+                    p95_us: histogram.value_at_quantile(0.95),
+                    p99_us: histogram.value_at_quantile(0.99),
+                }
+            })
+            .collect();
+        latencies.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.path.cmp(&b.path)));
+        latencies
+    }
+
+    // Increment the count of the IP's country if a GeoIP database is loaded
+    fn increment_country_count(&self, ip: IpAddr) {
+        if let Some(geoip) = &self.geoip {
+            let country = country_code(geoip, ip).unwrap_or(UNKNOWN_COUNTRY);
+            self.country_counts.increment(country);
+        }
+    }
+
+    // Increment request path count
+    fn increment_path_count(&self, path: &str) {
+        self.path_counts.increment(path);
+    }
+
+    // Remember that the IP requested the path, unless its set is full
+    fn record_ip_path(&self, ip: IpAddr, path: &str) {
+        let mut paths = self.paths_per_ip.entry(ip).or_default();
+        if paths.len() < MAX_DISTINCT_PATHS && !paths.contains(path) {
+            paths.insert(path.to_owned());
+        }
+    }
+
+    // Number of distinct paths the IP requested, capped at MAX_DISTINCT_PATHS
+    fn distinct_paths(&self, ip: &IpAddr) -> usize {
+        self.paths_per_ip.get(ip).map_or(0, |paths| paths.len())
+    }
+
+    // Get IPs that requested at least `min_paths` distinct paths, most paths first
+    fn get_scanners(&self, min_paths: usize) -> Vec<IpPaths> {
+        let mut scanners: Vec<IpPaths> = self
+            .paths_per_ip
+            .iter()
+            .filter(|entry| entry.value().len() >= min_paths)
+            .map(|entry| IpPaths {
+                ip: *entry.key(),
+                distinct_paths: entry.value().len(),
+            })
+            .collect();
+        scanners.sort_by(|a, b| {
+            b.distinct_paths
+                .cmp(&a.distinct_paths)
+                .then(a.ip.cmp(&b.ip))
+        });
+        scanners
+    }
+
+    // Increment HTTP method count
+    fn increment_method_count(&self, method: &Method) {
+        self.method_counts.increment(method);
+    }
+
+    // Record a request in the IP's recent one-second buckets
+    fn record_recent(&self, ip: IpAddr, now: Instant) {
+        let mut buckets = self.recent.entry(ip).or_default();
+
+        match buckets.back_mut() {
+            Some((start, count)) if now.duration_since(*start) < Duration::from_secs(1) => {
+                *count += 1;
+            }
+            _ => buckets.push_back((now, 1)),
+        }
+
+        // Keep a busy IP's buckets bounded even when pruning doesn't run
+        while buckets
+            .front()
+            .is_some_and(|(start, _)| now.duration_since(*start) > MAX_RECENT_WINDOW)
+        {
+            buckets.pop_front();
+        }
+    }
+
+    // Get sorted request counts per IP within the last `window`
+    fn get_recent_counts(&self, window: Duration) -> Vec<(IpAddr, u64)> {
+        self.recent_counts_at(window, Instant::now())
+    }
+
+    // Get sorted total request counts of the IPs last seen within the last `window`
+    fn get_active_ip_counts(&self, window: Duration) -> Vec<(IpAddr, u64)> {
+        self.active_ip_counts_at(window, Instant::now())
+    }
+
+    // Get sorted total request counts of the IPs last seen within `window` before `now`
+    fn active_ip_counts_at(&self, window: Duration, now: Instant) -> Vec<(IpAddr, u64)> {
+        let mut counts: Vec<_> = self
+            .ip_counts
+            .details()
+            .into_iter()
+            .filter(|(_, details)| now.saturating_duration_since(details.last_seen) <= window)
+            .map(|(ip, details)| (ip, details.count))
+            .collect();
+        counts.sort_by(|(_, a), (_, b)| b.cmp(a));
+        counts
+    }
+
+    // Get sorted request counts per IP within `window` before `now`
+    fn recent_counts_at(&self, window: Duration, now: Instant) -> Vec<(IpAddr, u64)> {
+        let mut counts: Vec<_> = self
+            .recent
+            .iter()
+            .map(|entry| {
+                let count: u64 = entry
+                    .value()
+                    .iter()
+                    .filter(|(start, _)| now.duration_since(*start) < window)
+                    .map(|(_, count)| count)
+                    .sum();
+                (*entry.key(), count)
+            })
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        counts.sort_by(|(_, a), (_, b)| b.cmp(a));
+        counts
+    }
+
+    // Drop expired recent buckets and IPs without any left
+    fn prune_recent(&self, now: Instant) {
+        self.recent.retain(|_, buckets| {
+            buckets.retain(|(start, _)| now.duration_since(*start) <= MAX_RECENT_WINDOW);
+            !buckets.is_empty()
+        });
+    }
+
+    // Clear all accumulated counts
+    fn reset(&self) {
+        self.ip_counts.clear();
+        self.path_counts.clear();
+        self.paths_per_ip.clear();
+        self.method_counts.clear();
+        self.status_counts.clear();
+        self.bytes_in.clear();
+        self.bytes_out.clear();
+        self.latencies.clear();
+        self.country_counts.clear();
+        self.recent.clear();
+        self.rates.clear();
+        self.banned.clear();
+        self.alerted.clear();
+        self.history
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+        self.history_pending.store(0, Ordering::Relaxed);
+        self.baseline
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    // Capture the current IP counts as the baseline of get_counts_since_mark
+    fn mark(&self) {
+        let counts = self.ip_counts.snapshot().into_iter().collect();
+        *self.baseline.lock().unwrap_or_else(PoisonError::into_inner) = counts;
+    }
+
+    // Capture the current IP counts under the name, replacing an earlier capture of it
+    // Returns false without capturing if there are too many other snapshots already
+    fn capture_snapshot(&self, name: &str) -> bool {
+        if !self.named_snapshots.contains_key(name)
+            && self.named_snapshots.len() >= MAX_NAMED_SNAPSHOTS
+        {
+            return false;
+        }
+        let counts = self.ip_counts.snapshot().into_iter().collect();
+        self.named_snapshots.insert(name.to_owned(), counts);
+        true
+    }
+
+    // Get the change of every IP's count between two named snapshots, largest growth
+    // first, counting IPs missing from one of them as 0 there
+    // Returns None if either snapshot doesn't exist
+    fn diff_snapshots(&self, from: &str, to: &str) -> Option<Vec<IpDiff>> {
+        let from = self.named_snapshots.get(from)?.clone();
+        let to = self.named_snapshots.get(to)?.clone();
+
+        let ips: HashSet<IpAddr> = from.keys().chain(to.keys()).copied().collect();
+        let mut diffs: Vec<IpDiff> = ips
+            .into_iter()
+            .map(|ip| {
+                let from = from.get(&ip).copied().unwrap_or(0);
+                let to = to.get(&ip).copied().unwrap_or(0);
+                IpDiff {
+                    ip,
+                    from,
+                    to,
+                    change: to as i64 - from as i64,
+                }
+            })
+            .filter(|diff| diff.change != 0)
+            .collect();
+        diffs.sort_by(|a, b| b.change.cmp(&a.change).then(a.ip.cmp(&b.ip)));
+        Some(diffs)
+    }
+
+    // Get sorted IP counts gained since the last mark, all of them if never marked
+    // IPs whose count didn't grow, e.g. after decaying, are left out
+    fn get_counts_since_mark(&self) -> Vec<(IpAddr, u64)> {
+        let baseline = self.baseline.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut counts: Vec<_> = self
+            .ip_counts
+            .snapshot()
+            .into_iter()
+            .map(|(ip, count)| {
+                let marked = baseline.get(&ip).copied().unwrap_or(0);
+                (ip, count.saturating_sub(marked))
+            })
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        counts.sort_by(|(_, a), (_, b)| b.cmp(a));
+        counts
+    }
+
+    // Get sorted IP counts
+    pub fn get_sorted_ip_counts(&self) -> Vec<(IpAddr, u64)> {
+        self.get_ip_counts_sorted_by(SortOrder::CountDesc)
+    }
+
+    // Get IP counts in the given order
+    fn get_ip_counts_sorted_by(&self, order: SortOrder) -> Vec<(IpAddr, u64)> {
+        match order {
+            SortOrder::CountDesc => self.ip_counts.sorted(),
+            SortOrder::CountAsc => {
+                let mut counts = self.ip_counts.snapshot();
+                counts.sort_by_key(|&(_, count)| count);
+                counts
+            }
+            SortOrder::Ip => {
+                let mut counts = self.ip_counts.snapshot();
+                counts.sort_by_key(|&(ip, _)| ip);
+                counts
+            }
+        }
+    }
+
+    // Get the first `top` IP counts in the given order, or all of them when unset
+    fn get_top_ip_counts(&self, top: Option<usize>, order: SortOrder) -> Vec<(IpAddr, u64)> {
+        let mut counts = self.get_ip_counts_sorted_by(order);
+        if let Some(top) = top {
+            counts.truncate(top);
+        }
+        counts
+    }
+
+    // Get sorted request path counts
+    fn get_sorted_path_counts(&self) -> Vec<(String, u64)> {
+        self.path_counts.sorted()
+    }
+
+    // Count the IPs in each bucket of request counts, split at the ascending
+    // inclusive upper `bounds`, with a final bucket for counts above the last one
+    fn get_count_histogram(&self, bounds: &[u64]) -> Vec<CountBucket> {
+        let mut buckets: Vec<CountBucket> = bounds
+            .iter()
+            .scan(1, |min, &max| {
+                let bucket = CountBucket {
+                    min: *min,
+                    max: Some(max),
+                    ips: 0,
+                };
+                *min = max.saturating_add(1);
+                Some(bucket)
+            })
+            .collect();
+        buckets.push(CountBucket {
+            min: bounds.last().map_or(1, |last| last.saturating_add(1)),
+            max: None,
+            ips: 0,
+        });
+
+        for (_, count) in self.ip_counts.snapshot() {
+            buckets[bounds.partition_point(|&bound| bound < count)].ips += 1;
+        }
+        buckets
+    }
+
+    // Get sorted request counts aggregated by network prefix
+    fn get_counts_by_prefix(&self, v4_bits: u8, v6_bits: u8) -> Vec<(IpAddr, u8, u64)> {
+        let mut prefixes: HashMap<IpAddr, u64> = HashMap::new();
+        for (ip, count) in self.get_sorted_ip_counts() {
+            *prefixes
+                .entry(network_prefix(ip, v4_bits, v6_bits))
+                .or_default() += count;
+        }
+
+        let mut counts: Vec<_> = prefixes
+            .into_iter()
+            .map(|(network, count)| {
+                let bits = if network.is_ipv4() { v4_bits } else { v6_bits };
+                (network, bits, count)
+            })
+            .collect();
+        counts.sort_by(|(_, _, a), (_, _, b)| b.cmp(a));
+        counts
+    }
+
+    // Copy IP counts into the persistence format
+    fn to_persisted(&self) -> PersistedCounts {
+        self.ip_counts.snapshot().into_iter().collect()
+    }
+
+    // Add previously persisted IP counts on top of the current ones
+    fn restore(&self, counts: PersistedCounts) {
+        for (ip, count) in counts {
+            self.ip_counts.add(&ip, count);
+        }
+    }
+
+    // Add another instance's IP counts on top of the current ones
+    // Kept for the planned multi-instance aggregator, which has no endpoint yet
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn merge(&self, other: &AppState) {
+        for (ip, count) in other.ip_counts.snapshot() {
+            self.ip_counts.add(&ip, count);
+        }
+    }
+
+    // Replace the IP counts with persisted ones
+    // Readers may briefly see no counts between clearing and restoring
+    fn replace_counts(&self, counts: PersistedCounts) {
+        self.ip_counts.clear();
+        self.restore(counts);
+    }
+
+    // Request count of the IP, if it was ever seen
+    fn count_for(&self, ip: &IpAddr) -> Option<u64> {
+        self.ip_counts.get(ip)
+    }
+
+    // Stop tracking the IP, returning its count if it was ever seen
+    fn remove_ip(&self, ip: &IpAddr) -> Option<u64> {
+        self.alerted.remove(ip);
+        self.banned.remove(ip);
+        self.paths_per_ip.remove(ip);
+        self.baseline
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(ip);
+        self.ip_counts.remove(ip)
+    }
+
+    // Total number of counted requests across all IPs
+    fn total_requests(&self) -> u64 {
+        self.ip_counts.total()
+    }
+
+    // IP with the highest count, the lowest address among equal counts
+    fn top_ip(&self) -> Option<(IpAddr, u64)> {
+        self.ip_counts
+            .snapshot()
+            .into_iter()
+            .max_by_key(|&(ip, count)| (count, std::cmp::Reverse(ip)))
+    }
+
+    // Total requests counted from IPv4 addresses
+    fn ipv4_request_total(&self) -> u64 {
+        self.request_total_where(IpAddr::is_ipv4)
+    }
+
+    // Total requests counted from IPv6 addresses
+    fn ipv6_request_total(&self) -> u64 {
+        self.request_total_where(IpAddr::is_ipv6)
+    }
+
+    // Total requests counted from IPs matching the predicate
+    fn request_total_where(&self, predicate: impl Fn(&IpAddr) -> bool) -> u64 {
+        self.ip_counts
+            .snapshot()
+            .into_iter()
+            .filter(|(ip, _)| predicate(ip))
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    // Number of distinct IPs counted
+    fn unique_ip_count(&self) -> usize {
+        self.ip_counts.len()
+    }
+
+    // Get sorted IP counts as serializable entries
+    fn get_ip_count_entries(&self, top: Option<usize>, order: SortOrder) -> Vec<IpCount> {
+        self.get_top_ip_counts(top, order)
+            .into_iter()
+            .map(|(ip, count)| IpCount { ip, count })
+            .collect()
+    }
+
+    // Get IP counts with their timestamps sorted by descending count
+    fn get_detailed_ip_counts(&self) -> Vec<IpDetails> {
+        // Convert every timestamp against the same pair of clock readings
+        let now = Instant::now();
+        let wall_now = SystemTime::now();
+        let unix_seconds = |instant: Instant| {
+            (wall_now - now.saturating_duration_since(instant))
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs())
+        };
+
+        let mut details = self.ip_counts.details();
+        details.sort_by_key(|(_, details)| std::cmp::Reverse(details.count));
+        details
+            .into_iter()
+            .map(|(ip, details)| IpDetails {
+                ip,
+                count: details.count,
+                first_seen: unix_seconds(details.first_seen),
+                last_seen: unix_seconds(details.last_seen),
+            })
+            .collect()
+    }
+
+    // Format IP statistics
+    // With --stats-top, IPs past the busiest N are summed up on an "other" line
+    fn format_ip_stats(&self) -> String {
+        let mut counts = self.get_sorted_ip_counts();
+        let rest = match self.stats_top {
+            Some(top) if counts.len() > top => counts.split_off(top),
+            _ => Vec::new(),
+        };
+        let mut result = self.format_ip_lines(counts, self.color);
+        if !rest.is_empty() {
+            let sum: u64 = rest.iter().map(|(_, count)| count).sum();
+            result.push_str(&format!("  other ({} ips): {}\n", rest.len(), sum));
+        }
+        result
+    }
+
+    // Format the given IP counts
+    fn format_ip_counts(&self, counts: Vec<(IpAddr, u64)>) -> String {
+        self.format_ip_lines(counts, false)
+    }
+
+    // Format the given IP counts, highlighting the busiest ones if `color` is set
+    // Only the printer passes `color`, so escape codes never end up in responses
+    fn format_ip_lines(&self, counts: Vec<(IpAddr, u64)>, color: bool) -> String {
+        let label = self.stats_label.as_deref().unwrap_or("IPs");
+        let mut result = format!("{}:\n", label);
+        // Shares are of all requests, even when only the top counts are given
+        let total = self.stats_percent.then(|| self.total_requests());
+        let busiest = counts.iter().map(|(_, count)| *count).max().unwrap_or(0);
+        for (ip, count) in counts {
+            let hostname = match self.hostnames.get(&ip).as_deref() {
+                Some(Some(hostname)) => format!(" ({})", hostname),
+                _ => String::new(),
+            };
+            let share = match total {
+                Some(total) => format!(" ({:.1}%)", percent_of(count, total)),
+                None => String::new(),
+            };
+            let banned = if self.banned.contains(&ip) {
+                " (banned)"
+            } else {
+                ""
+            };
+            let line = format!("{}{}: {}{}{}", ip, hostname, count, share, banned);
+            match highlight(count, busiest).filter(|_| color) {
+                Some(code) => result.push_str(&format!("  {}{}{}\n", code, line, ANSI_RESET)),
+                None => result.push_str(&format!("  {}\n", line)),
+            }
+        }
+        result
+    }
+
+    // Format request path statistics
+    fn format_path_stats(&self) -> String {
+        let counts = self.get_sorted_path_counts();
+        let mut result = String::from("Paths:\n");
+        for (path, count) in counts {
+            result.push_str(&format!("  {}: {}\n", path, count));
+        }
+        result
+    }
+
+    // Format HTTP method statistics
+    fn format_method_stats(&self) -> String {
+        let counts = self.method_counts.sorted();
+        let mut result = String::from("Methods:\n");
+        for (method, count) in counts {
+            result.push_str(&format!("  {}: {}\n", method, count));
+        }
+        result
+    }
+
+    // Format country statistics
+    fn format_country_stats(&self) -> String {
+        let counts = self.country_counts.sorted();
+        let mut result = String::from("Countries:\n");
+        for (country, count) in counts {
+            result.push_str(&format!("  {}: {}\n", country, count));
+        }
+        result
+    }
+
+    // Format response status statistics
+    fn format_status_stats(&self) -> String {
+        let counts = self.status_counts.sorted();
+        let mut result = String::from("Statuses:\n");
+        for (status, count) in counts {
+            result.push_str(&format!("  {}: {}\n", status, count));
+        }
+        result
+    }
+
+    // Format all statistics for the console
+    fn format_stats(&self) -> String {
+        format!(
+            "{}{}{}{}",
+            self.format_ip_stats(),
+            self.format_path_stats(),
+            self.format_method_stats(),
+            self.format_status_stats()
+        )
+    }
+
+    // Format IP counts as CSV with a header row
+    // IP addresses never contain commas or quotes, so no field needs quoting
+    fn format_ip_csv(&self) -> String {
+        let mut result = String::from("ip,count\n");
+        for (ip, count) in self.get_sorted_ip_counts() {
+            result.push_str(&format!("{},{}\n", ip, count));
+        }
+        result
+    }
+
+    // Format IP statistics in the Prometheus text exposition format
+    fn format_prometheus_metrics(&self) -> String {
+        let counts = self.get_sorted_ip_counts();
+        let mut result = String::from(
+            "# HELP tomoru_requests_total Total number of HTTP requests per client IP.\n\
+             # TYPE tomoru_requests_total counter\n",
+        );
+        for (ip, count) in counts {
+            result.push_str(&format!(
+                "tomoru_requests_total{{ip=\"{}\"}} {}\n",
+                ip, count
+            ));
+        }
+
+        result.push_str(&format!(
+            "# HELP tomoru_requests_served_total Total number of HTTP requests counted.\n\
+             # TYPE tomoru_requests_served_total counter\n\
+             tomoru_requests_served_total {}\n\
+             # HELP tomoru_unique_ips Number of distinct client IPs counted.\n\
+             # TYPE tomoru_unique_ips gauge\n\
+             tomoru_unique_ips {}\n\
+             # HELP tomoru_in_flight_requests Number of HTTP requests being handled.\n\
+             # TYPE tomoru_in_flight_requests gauge\n\
+             tomoru_in_flight_requests {}\n",
+            self.requests_served.load(Ordering::Relaxed),
+            self.unique_ip_count(),
+            self.in_flight.load(Ordering::Relaxed)
+        ));
+        if let Some(started) = self.started {
+            result.push_str(&format!(
+                "# HELP tomoru_uptime_seconds Seconds since the server started.\n\
+                 # TYPE tomoru_uptime_seconds gauge\n\
+                 tomoru_uptime_seconds {}\n",
+                started.elapsed().as_secs()
+            ));
+        }
+        result
+    }
+
+    // Format aggregate gauges as StatsD lines
+    // Per-IP counts are left out, since every IP would become a metric of its own
+    #[cfg_attr(not(feature = "stats-printer"), allow(dead_code))]
+    fn format_statsd(&self) -> String {
+        format!(
+            "tomoru.requests.total:{}|g\n\
+             tomoru.ips.unique:{}|g\n\
+             tomoru.ips.top_count:{}|g\n",
+            self.total_requests(),
+            self.unique_ip_count(),
+            self.top_ip().map_or(0, |(_, count)| count)
+        )
+    }
+
+    // Mark the server as draining and request a graceful shutdown
+    fn drain(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+        self.shutdown.cancel();
+    }
+
+    fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+}
+
+const ANSI_RED: &str = "\x1b[31m";
+const ANSI_YELLOW: &str = "\x1b[33m";
+const ANSI_RESET: &str = "\x1b[0m";
+
+// Color of an IP's line in the printed stats, relative to the busiest IP's count:
+// red from half of it, yellow from a tenth of it, uncolored below
+fn highlight(count: u64, busiest: u64) -> Option<&'static str> {
+    if count == 0 {
+        None
+    } else if count * 2 >= busiest {
+        Some(ANSI_RED)
+    } else if count * 10 >= busiest {
+        Some(ANSI_YELLOW)
+    } else {
+        None
+    }
+}
+
+// Share of the total that the count makes up, in percent
+fn percent_of(count: u64, total: u64) -> f64 {
+    if total == 0 {
+        return 0.0;
+    }
+    count as f64 * 100.0 / total as f64
+}
+
+// Counts a request as in flight until dropped, so it also stops being
+// in flight when its connection is closed before the handler completes
+struct InFlight<'a>(&'a AtomicU64);
+
+impl<'a> InFlight<'a> {
+    fn start(in_flight: &'a AtomicU64) -> Self {
+        in_flight.fetch_add(1, Ordering::Relaxed);
+        Self(in_flight)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Body size from the Content-Length header, falling back to the body's exact size
+// Streamed bodies of unknown length, such as chunked ones, count as 0 bytes
+fn body_size(headers: &HeaderMap, body: &Body) -> u64 {
+    headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok()?.parse().ok())
+        .or_else(|| body.size_hint().exact())
+        .unwrap_or(0)
+}
+
+// Look up the ISO country code of the IP
+fn country_code(geoip: &maxminddb::Reader<Vec<u8>>, ip: IpAddr) -> Option<&str> {
+    geoip
+        .lookup(ip)
+        .ok()?
+        .decode_path(&maxminddb::path!["country", "iso_code"])
+        .ok()?
+}
+
+// Load a MaxMind DB to resolve countries with
+fn load_geoip_db(path: &Path) -> Result<maxminddb::Reader<Vec<u8>>> {
+    maxminddb::Reader::open_readfile(path)
+        .with_context(|| format!("Failed to load GeoIP database {}", path.display()))
+}
+
+// Parse a single IP address from the given header
+fn header_ip(headers: &HeaderMap, name: &HeaderName) -> Option<IpAddr> {
+    headers.get(name)?.to_str().ok()?.trim().parse().ok()
+}
+
+// Parse the leftmost (original client) address from the X-Forwarded-For header
+fn forwarded_for_ip(headers: &HeaderMap) -> Option<IpAddr> {
+    headers
+        .get("x-forwarded-for")?
+        .to_str()
+        .ok()?
+        .split(',')
+        .next()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+// Header carrying the ID that ties a request's log lines together across services
+const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// Longest incoming request ID kept, longer ones are replaced by a generated one
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+// Generate a random UUID v4 as a request ID
+fn new_request_id() -> HeaderValue {
+    let bits = fastrand::u128(..);
+    // Set the version (4) and variant (RFC 4122) bits
+    let bits = bits & !(0xF << 76) | (0x4 << 76);
+    let bits = bits & !(0x3 << 62) | (0x2 << 62);
+    let id = format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        bits >> 96,
+        (bits >> 80) & 0xFFFF,
+        (bits >> 64) & 0xFFFF,
+        (bits >> 48) & 0xFFFF,
+        bits & 0xFFFF_FFFF_FFFF
+    );
+    HeaderValue::try_from(id).expect("UUIDs are valid header values")
+}
+
+/// Keeps the incoming X-Request-Id or generates one, passing it on to the
+/// handlers and echoing it in the response
+async fn request_id_middleware(mut request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(REQUEST_ID)
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.to_str().is_ok())
+        .cloned()
+        .unwrap_or_else(new_request_id);
+    request.headers_mut().insert(REQUEST_ID, id.clone());
+
+    let mut response = next.run(request).await;
+    response.headers_mut().insert(REQUEST_ID, id);
+    response
+}
+
+// Peer address that Unix domain socket connections are counted under
+const UDS_PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// Tracks request count per IP address and forwards the request
+///
+/// Requests are counted before the blocklist, ban and rate limit are checked,
+/// so rejected requests still show up in the stats
+async fn counter_middleware(
+    State(app_state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    // Connections over a Unix domain socket have no peer address
+    let addr = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map_or(UDS_PEER, |ConnectInfo(addr)| *addr);
+    let client_ip = app_state.client_ip(request.headers(), addr);
+    // Uncounted requests skip the counting, the rate limiter and new bans, but are still
+    // refused if blocked or already banned, and tracked and logged like any other
+    let counted = !app_state.replica
+        && !app_state.ignored_ips.contains(&client_ip)
+        && app_state.counts_path(request.uri().path());
+    // Past this point only the masked IP is stored or logged, if anonymizing
+    let ip = if app_state.anonymize {
+        anonymize_ip(client_ip)
+    } else {
+        client_ip
+    };
+
+    let start = Instant::now();
+    // Repeats are still checked against bans and limits below, just not counted again
+    let repeat = counted && app_state.is_repeat(ip, request.headers(), start);
+    let count = if !counted || repeat {
+        app_state.count_for(&ip).unwrap_or(0)
+    } else {
+        let count = app_state.increment_ip_count(ip);
+        app_state.increment_path_count(request.uri().path());
+        app_state.record_ip_path(ip, request.uri().path());
+        app_state.increment_method_count(request.method());
+        app_state.increment_country_count(ip);
+        app_state.record_recent(ip, start);
+        count
+    };
+
+    // The request is consumed by the handler, so keep what the access log needs
+    let access_log = app_state.access_log.then(|| {
+        let request_id = request
+            .headers()
+            .get(REQUEST_ID)
+            .and_then(|id| id.to_str().ok())
+            .unwrap_or_default()
+            .to_owned();
+        (
+            request.method().clone(),
+            request.uri().path().to_owned(),
+            request_id,
+        )
+    });
+    // Latencies are kept per route, so their number stays bounded
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or(UNMATCHED_PATH, MatchedPath::as_str)
+        .to_owned();
+    let bytes_in = body_size(request.headers(), request.body());
+
+    // Blocked and already banned IPs are refused whether or not the request is counted
+    let rejection = if app_state.is_blocked(client_ip)
+        || app_state.banned.contains(&ip)
+        || (counted && app_state.check_ban(ip, count))
+    {
+        Some(ApiError::Forbidden)
+    } else if counted && !app_state.allow_request(ip) {
+        Some(ApiError::TooManyRequests)
+    } else {
+        None
+    };
+    let response = match rejection {
+        Some(error) => error.into_response(),
+        None => {
+            let _in_flight = InFlight::start(&app_state.in_flight);
+            next.run(request).await
+        }
+    };
+
+    // The IP was already counted above, only the status is known this late
+    let latency = start.elapsed();
+    if counted && !repeat {
+        app_state.increment_status_count(response.status());
+        app_state.record_latency(&route, latency);
+        app_state.record_bandwidth(ip, bytes_in, body_size(response.headers(), response.body()));
+    }
+
+    if let Some((method, path, request_id)) = access_log {
+        info!(
+            target: "tomoru::access",
+            %ip,
+            %method,
+            path,
+            request_id,
+            status = response.status().as_u16(),
+            latency_ms = latency.as_secs_f64() * 1000.0,
+            "Request served"
+        );
+    }
+    response
+}
+
+/// Basic /ping endpoint answering with the configured --ping-response,
+/// or 503 once the server is draining
+async fn ping(State(app_state): State<Arc<AppState>>, response: String) -> Response {
+    if app_state.is_draining() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "draining").into_response();
+    }
+    response.into_response()
+}
+
+// Order of the IP counts returned by /stats and /stats.json
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum SortOrder {
+    // Busiest IPs first
+    #[default]
+    CountDesc,
+    // Least active IPs first
+    CountAsc,
+    // By address, IPv4 before IPv6
+    Ip,
+}
+
+// Query parameters of the /stats and /stats.json endpoints
+#[derive(Deserialize)]
+struct TopParams {
+    // Only return this many of the first counts in the sort order
+    top: Option<usize>,
+    #[serde(default)]
+    sort: SortOrder,
+}
+
+/// Liveness probe endpoint
+///
+/// Unlike /ping it's not counted, so orchestrators can probe
+/// it as often as they like without inflating the stats
+async fn healthz() -> StatusCode {
+    StatusCode::OK
+}
+
+/// Fallback for unknown paths, responding with a JSON 404
+///
+/// It's behind the counter layer, so scanners probing
+/// random paths still show up in the stats
+async fn not_found() -> ApiError {
+    ApiError::NotFound("Not found")
+}
+
+/// Returns the crate version and git commit of the running build as JSON
+///
+/// Not counted, like /healthz
+async fn version() -> Json<VersionInfo> {
+    Json(VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_commit: option_env!("TOMORU_GIT_COMMIT"),
+    })
+}
+
+/// Returns current request statistics as plain text
+///
+/// Requests to /stats pass through the counter middleware as well,
+/// so they are included in the returned counts
+async fn stats_text(
+    State(app_state): State<Arc<AppState>>,
+    ApiQuery(params): ApiQuery<TopParams>,
+) -> String {
+    app_state.format_ip_counts(app_state.get_top_ip_counts(params.top, params.sort))
+}
+
+/// Returns current request statistics as JSON
+async fn stats_json(
+    State(app_state): State<Arc<AppState>>,
+    ApiQuery(params): ApiQuery<TopParams>,
+) -> Json<Vec<IpCount>> {
+    Json(app_state.get_ip_count_entries(params.top, params.sort))
+}
+
+/// Returns the total request count and the number of unique IPs as JSON
+async fn stats_summary(State(app_state): State<Arc<AppState>>) -> Json<Summary> {
+    Json(Summary {
+        total_requests: app_state.total_requests(),
+        unique_ips: app_state.unique_ip_count(),
+        ipv4_requests: app_state.ipv4_request_total(),
+        ipv6_requests: app_state.ipv6_request_total(),
+        top_ip: app_state.top_ip().map(|(ip, count)| IpCount { ip, count }),
+    })
+}
+
+/// Returns request counts per IP with first and last seen timestamps as JSON
+async fn stats_detailed(State(app_state): State<Arc<AppState>>) -> Json<Vec<IpDetails>> {
+    Json(app_state.get_detailed_ip_counts())
+}
+
+// Query parameters of the /stats/recent endpoint
+#[derive(Deserialize)]
+struct RecentParams {
+    // Window length in seconds
+    window: Option<u64>,
+}
+
+/// Returns request counts per IP within the last `window` seconds as JSON
+///
+/// Windows longer than MAX_RECENT_WINDOW are capped to it
+async fn stats_recent(
+    State(app_state): State<Arc<AppState>>,
+    ApiQuery(params): ApiQuery<RecentParams>,
+) -> Json<Vec<IpCount>> {
+    let window = Duration::from_secs(params.window.unwrap_or(60)).min(MAX_RECENT_WINDOW);
+    let entries = app_state
+        .get_recent_counts(window)
+        .into_iter()
+        .map(|(ip, count)| IpCount { ip, count })
+        .collect();
+    Json(entries)
+}
+
+// Query parameters of the /stats/active endpoint
+#[derive(Deserialize)]
+struct ActiveParams {
+    // Window length in seconds
+    window: Option<u64>,
+}
+
+/// Returns the total request counts of the IPs seen within the last `window`
+/// seconds as JSON, leaving out the idle ones
+async fn stats_active(
+    State(app_state): State<Arc<AppState>>,
+    ApiQuery(params): ApiQuery<ActiveParams>,
+) -> Json<Vec<IpCount>> {
+    let window = Duration::from_secs(params.window.unwrap_or(30));
+    let entries = app_state
+        .get_active_ip_counts(window)
+        .into_iter()
+        .map(|(ip, count)| IpCount { ip, count })
+        .collect();
+    Json(entries)
+}
+
+/// Returns the approximate current requests per second of each IP as JSON
+///
+/// Rates are exponentially decaying averages with a time constant of RATE_TIME_CONSTANT
+async fn stats_rates(State(app_state): State<Arc<AppState>>) -> Json<Vec<IpRate>> {
+    Json(app_state.rates_at(Instant::now()))
+}
+
+/// Captures the current IP counts as the baseline of /stats/since
+async fn stats_mark(State(app_state): State<Arc<AppState>>) -> &'static str {
+    app_state.mark();
+    "marked"
+}
+
+/// Returns the requests per IP counted since the last /stats/mark as JSON
+async fn stats_since(State(app_state): State<Arc<AppState>>) -> Json<Vec<IpCount>> {
+    let entries = app_state
+        .get_counts_since_mark()
+        .into_iter()
+        .map(|(ip, count)| IpCount { ip, count })
+        .collect();
+    Json(entries)
+}
+
+/// Captures the current IP counts under the name for /stats/diff
+async fn stats_snapshot(
+    State(app_state): State<Arc<AppState>>,
+    ApiPath(name): ApiPath<String>,
+) -> Result<&'static str, ApiError> {
+    if !app_state.capture_snapshot(&name) {
+        return Err(ApiError::BadRequest("Too many snapshots"));
+    }
+    Ok("captured")
+}
+
+// Query parameters of the /stats/diff endpoint
+#[derive(Deserialize)]
+struct DiffParams {
+    // Names of the earlier and the later snapshot
+    from: String,
+    to: String,
+}
+
+/// Returns how each IP's count changed between two named snapshots as JSON
+async fn stats_diff(
+    State(app_state): State<Arc<AppState>>,
+    ApiQuery(params): ApiQuery<DiffParams>,
+) -> Result<Json<Vec<IpDiff>>, ApiError> {
+    app_state
+        .diff_snapshots(&params.from, &params.to)
+        .map(Json)
+        .ok_or(ApiError::NotFound("Snapshot not found"))
+}
+
+/// Returns the total requests of each minute within --history-minutes as JSON, oldest first
+async fn stats_timeseries(State(app_state): State<Arc<AppState>>) -> Json<Vec<MinuteCount>> {
+    Json(app_state.get_timeseries())
+}
+
+/// Returns the request count and distinct paths of a single IP as JSON,
+/// or 404 if it was never seen
+async fn stats_ip(
+    State(app_state): State<Arc<AppState>>,
+    ApiPath(addr): ApiPath<String>,
+) -> Result<Json<IpLookup>, ApiError> {
+    let ip: IpAddr = addr
+        .parse()
+        .map_err(|_| ApiError::BadRequest("Invalid IP address"))?;
+    let count = app_state
+        .count_for(&ip)
+        .ok_or(ApiError::NotFound("IP address not seen"))?;
+    Ok(Json(IpLookup {
+        ip,
+        count,
+        distinct_paths: app_state.distinct_paths(&ip),
+    }))
+}
+
+// Query parameters of the /stats/scanners endpoint
+#[derive(Deserialize)]
+struct ScannerParams {
+    // Fewest distinct paths an IP must have requested to be listed
+    min_paths: Option<usize>,
+}
+
+/// Returns the IPs that requested at least `min_paths` distinct paths as JSON
+///
+/// Each IP's paths are only remembered up to MAX_DISTINCT_PATHS
+async fn stats_scanners(
+    State(app_state): State<Arc<AppState>>,
+    ApiQuery(params): ApiQuery<ScannerParams>,
+) -> Json<Vec<IpPaths>> {
+    Json(app_state.get_scanners(params.min_paths.unwrap_or(10)))
+}
+
+// Query parameters of the /stats/histogram endpoint
+#[derive(Deserialize)]
+struct HistogramParams {
+    // Comma-separated ascending upper bounds of the buckets
+    bounds: Option<String>,
+}
+
+// Parse comma-separated bucket bounds, which must be positive and strictly ascending
+fn parse_histogram_bounds(value: &str) -> Result<Vec<u64>, ApiError> {
+    const INVALID: ApiError =
+        ApiError::BadRequest("Bounds must be positive, ascending and separated by commas");
+    let bounds = value
+        .split(',')
+        .map(|bound| bound.trim().parse::<u64>().map_err(|_| INVALID))
+        .collect::<Result<Vec<_>, _>>()?;
+    if bounds.first() == Some(&0) || bounds.windows(2).any(|pair| pair[0] >= pair[1]) {
+        return Err(INVALID);
+    }
+    Ok(bounds)
+}
+
+/// Returns how many IPs made a number of requests within each bucket as JSON,
+/// by default 1, 2-10, 11-100 and so on
+async fn stats_histogram(
+    State(app_state): State<Arc<AppState>>,
+    ApiQuery(params): ApiQuery<HistogramParams>,
+) -> Result<Json<Vec<CountBucket>>, ApiError> {
+    let bounds = match params.bounds {
+        Some(bounds) => parse_histogram_bounds(&bounds)?,
+        None => DEFAULT_HISTOGRAM_BOUNDS.to_vec(),
+    };
+    Ok(Json(app_state.get_count_histogram(&bounds)))
+}
+
+// Query parameters of the /stats/subnets endpoint
+#[derive(Deserialize)]
+struct SubnetParams {
+    // IPv4 prefix length
+    v4: Option<u8>,
+    // IPv6 prefix length
+    v6: Option<u8>,
+}
+
+/// Returns request counts aggregated by network prefix as JSON
+async fn stats_subnets(
+    State(app_state): State<Arc<AppState>>,
+    ApiQuery(params): ApiQuery<SubnetParams>,
+) -> Result<Json<Vec<SubnetCount>>, ApiError> {
+    let v4_bits = params.v4.unwrap_or(24);
+    let v6_bits = params.v6.unwrap_or(64);
+    if v4_bits > 32 || v6_bits > 128 {
+        return Err(ApiError::BadRequest(
+            "Prefix lengths must be at most 32 for IPv4 and 128 for IPv6",
+        ));
+    }
+
+    let entries = app_state
+        .get_counts_by_prefix(v4_bits, v6_bits)
+        .into_iter()
+        .map(|(network, bits, count)| SubnetCount {
+            subnet: format!("{}/{}", network, bits),
+            count,
+        })
+        .collect();
+    Ok(Json(entries))
+}
+
+/// Returns current request counts per HTTP method as plain text
+async fn stats_methods(State(app_state): State<Arc<AppState>>) -> String {
+    app_state.format_method_stats()
+}
+
+/// Returns current request counts per response status code as plain text
+async fn stats_status(State(app_state): State<Arc<AppState>>) -> String {
+    app_state.format_status_stats()
+}
+
+/// Returns bytes received from and sent to each IP as JSON
+///
+/// Only bodies of known length are accounted, so streamed
+/// responses without a Content-Length count as 0 bytes
+async fn stats_bandwidth(State(app_state): State<Arc<AppState>>) -> Json<Vec<IpBandwidth>> {
+    Json(app_state.get_bandwidth())
+}
+
+/// Returns p50/p95/p99 request latencies per route as JSON,
+/// with requests matching no route under UNMATCHED_PATH
+async fn stats_latency(State(app_state): State<Arc<AppState>>) -> Json<Vec<PathLatency>> {
+    Json(app_state.get_latency_percentiles())
+}
+
+/// Upgrades to a WebSocket pushing the JSON request statistics every period
+async fn stats_ws(
+    ws: WebSocketUpgrade,
+    State(app_state): State<Arc<AppState>>,
+    period: Duration,
+) -> Response {
+    ws.on_upgrade(move |socket| stream_stats_ws(socket, app_state, period))
+}
+
+/// Sends the JSON request statistics every period until the client disconnects or shutdown is requested
+async fn stream_stats_ws(mut socket: WebSocket, app_state: Arc<AppState>, period: Duration) {
+    let mut interval = time::interval(period);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let entries = app_state.get_ip_count_entries(None, SortOrder::CountDesc);
+                let json = serde_json::to_string(&entries).expect("IP counts serialize to JSON");
+                if socket.send(Message::Text(json.into())).await.is_err() {
+                    break;
+                }
+            }
+            // Incoming messages are ignored, only closing the connection matters
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+            _ = app_state.shutdown.cancelled() => break,
+        }
+    }
+}
+
+/// Streams the JSON request statistics as Server-Sent Events every period until shutdown
+async fn stats_sse(
+    State(app_state): State<Arc<AppState>>,
+    period: Duration,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let interval = time::interval(period);
+    let events = stream::unfold(
+        (app_state, interval),
+        |(app_state, mut interval)| async move {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = app_state.shutdown.cancelled() => return None,
+            }
+            let event = Event::default()
+                .json_data(app_state.get_ip_count_entries(None, SortOrder::CountDesc));
+            Some((event, (app_state, interval)))
+        },
+    );
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+/// Returns current request counts per country as plain text
+async fn stats_countries(State(app_state): State<Arc<AppState>>) -> String {
+    app_state.format_country_stats()
+}
+
+/// Returns current request statistics in the Prometheus format
+async fn metrics(State(app_state): State<Arc<AppState>>) -> Response {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        app_state.format_prometheus_metrics(),
+    )
+        .into_response()
+}
+
+/// Returns request counts per IP as a CSV download
+async fn stats_csv(State(app_state): State<Arc<AppState>>) -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, "text/csv"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"tomoru-stats.csv\"",
+            ),
+        ],
+        app_state.format_ip_csv(),
+    )
+        .into_response()
+}
+
+/// Returns the IP counts as a JSON file in the --state-file format,
+/// e.g. to seed another instance with them
+async fn stats_export(State(app_state): State<Arc<AppState>>) -> Response {
+    match state_json(&app_state) {
+        Ok(json) => (
+            [
+                (header::CONTENT_TYPE, "application/json"),
+                (
+                    header::CONTENT_DISPOSITION,
+                    "attachment; filename=\"tomoru-state.json\"",
+                ),
+            ],
+            json,
+        )
+            .into_response(),
+        Err(e) => {
+            error!("State export error: {:#}", e);
+            ApiError::Internal.into_response()
+        }
+    }
+}
+
+/// Clears all accumulated counts
+async fn reset(State(app_state): State<Arc<AppState>>) -> &'static str {
+    app_state.reset();
+    "reset"
+}
+
+/// Clears the count of a single IP, returning what it was as JSON, or 404 if it was never seen
+async fn reset_ip(
+    State(app_state): State<Arc<AppState>>,
+    ApiPath(addr): ApiPath<String>,
+) -> Result<Json<IpCount>, ApiError> {
+    let ip: IpAddr = addr
+        .parse()
+        .map_err(|_| ApiError::BadRequest("Invalid IP address"))?;
+    let count = app_state
+        .remove_ip(&ip)
+        .ok_or(ApiError::NotFound("IP address not seen"))?;
+    Ok(Json(IpCount { ip, count }))
+}
+
+/// Starts a graceful shutdown, the same as Ctrl-C, for requests with the admin token
+async fn shutdown(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    admin_token: String,
+) -> Result<StatusCode, ApiError> {
+    if !has_bearer_token(&headers, &admin_token) {
+        return Err(ApiError::Unauthorized);
+    }
+    info!("Shutdown requested via /shutdown");
+    app_state.drain();
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Rejects requests without the --stats-user/--stats-pass credentials with 401
+async fn basic_auth(State(credentials): State<Arc<str>>, request: Request, next: Next) -> Response {
+    if !has_authorization(request.headers(), "Basic", &credentials) {
+        return (
+            [(header::WWW_AUTHENTICATE, "Basic realm=\"tomoru\"")],
+            ApiError::Unauthorized,
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+// Check for an "Authorization: Bearer <token>" header with the given token
+fn has_bearer_token(headers: &HeaderMap, token: &str) -> bool {
+    has_authorization(headers, "Bearer", token)
+}
+
+// Check for an "Authorization: <scheme> <credentials>" header with the given credentials,
+// compared in constant time so response times don't reveal how much matched
+fn has_authorization(headers: &HeaderMap, scheme: &str, credentials: &str) -> bool {
+    let Some(presented) = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.as_bytes().strip_prefix(scheme.as_bytes()))
+        .and_then(|value| value.strip_prefix(b" "))
+    else {
+        return false;
+    };
+    presented.len() == credentials.len()
+        && presented
+            .iter()
+            .zip(credentials.as_bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// Open the stats file for appending, creating it if needed
+async fn open_stats_file(path: &Path) -> Result<tokio::fs::File> {
+    tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .with_context(|| format!("Failed to open stats file {}", path.display()))
+}
+
+// Append the current request statistics with a timestamp to the stats file
+// The file is reopened every time, so rotating it away starts a new one
+#[cfg_attr(not(feature = "stats-printer"), allow(dead_code))]
+async fn append_stats(stats: &AppState, path: &Path) -> Result<()> {
+    let snapshot = format!(
+        "[{}]\n{}",
+        httpdate::fmt_http_date(SystemTime::now()),
+        stats.format_stats()
+    );
+    open_stats_file(path)
+        .await?
+        .write_all(snapshot.as_bytes())
+        .await
+        .with_context(|| format!("Failed to write stats file {}", path.display()))
+}
+
+// Log a human-readable snapshot of the statistics
+fn log_stats(stats: &AppState) {
+    log_stats_to(stats, &mut io::stdout());
+}
+
+// Log a human-readable snapshot of the statistics, writing colored ones to `out`
+// The log output escapes ANSI codes in messages, so with --color only the
+// heading is logged and the colored lines are written to `out` right after it
+fn log_stats_to(stats: &AppState, out: &mut impl io::Write) {
+    if !stats.color {
+        info!(
+            unique_ips = stats.unique_ip_count(),
+            "Request stats (IPv4: {}  IPv6: {}):\n{}",
+            stats.ipv4_request_total(),
+            stats.ipv6_request_total(),
+            stats.format_stats()
+        );
+        return;
+    }
+
+    info!(
+        unique_ips = stats.unique_ip_count(),
+        "Request stats (IPv4: {}  IPv6: {}):",
+        stats.ipv4_request_total(),
+        stats.ipv6_request_total()
+    );
+    if let Err(e) = out
+        .write_all(stats.format_stats().as_bytes())
+        .and_then(|()| out.flush())
+    {
+        warn!("Failed to print stats: {}", e);
+    }
+}
+
+// Resolve the StatsD server's address
+async fn resolve_statsd(addr: &str) -> Result<SocketAddr> {
+    tokio::net::lookup_host(addr)
+        .await
+        .with_context(|| format!("Failed to resolve StatsD server {}", addr))?
+        .next()
+        .with_context(|| format!("No address found for StatsD server {}", addr))
+}
+
+// Connect a UDP socket to the resolved StatsD server
+async fn connect_statsd(target: SocketAddr) -> Result<UdpSocket> {
+    let local = match target {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = UdpSocket::bind(local)
+        .await
+        .context("Failed to bind StatsD socket")?;
+    socket
+        .connect(target)
+        .await
+        .with_context(|| format!("Failed to connect to StatsD server {}", target))?;
+    Ok(socket)
+}
+
+// Send the aggregate gauges to StatsD in a single packet
+#[cfg_attr(not(feature = "stats-printer"), allow(dead_code))]
+async fn send_statsd(stats: &AppState, socket: &UdpSocket) -> Result<()> {
+    socket
+        .send(stats.format_statsd().as_bytes())
+        .await
+        .context("Failed to send StatsD metrics")?;
+    Ok(())
+}
+
+// Directory of the rotating state snapshots and how many of them are kept
+#[derive(Clone, Debug)]
+#[cfg_attr(not(feature = "stats-printer"), allow(dead_code))]
+struct Snapshots {
+    dir: PathBuf,
+    keep: usize,
+}
+
+// Write the IP counts to a snapshot named after the time, then delete
+// the oldest ones beyond the number kept
+#[cfg_attr(not(feature = "stats-printer"), allow(dead_code))]
+async fn rotate_snapshots(
+    stats: &AppState,
+    snapshots: &Snapshots,
+    unix_seconds: u64,
+) -> Result<()> {
+    tokio::fs::create_dir_all(&snapshots.dir)
+        .await
+        .with_context(|| format!("Failed to create {}", snapshots.dir.display()))?;
+    let path = snapshots.dir.join(format!("tomoru-{}.json", unix_seconds));
+    save_state(stats, &path).await?;
+    prune_snapshots(&snapshots.dir, snapshots.keep).await
+}
+
+// Delete all but the newest `keep` snapshots in the directory
+// Other files are left alone, and a snapshot that can't be deleted doesn't stop the rest
+#[cfg_attr(not(feature = "stats-printer"), allow(dead_code))]
+async fn prune_snapshots(dir: &Path, keep: usize) -> Result<()> {
+    let mut entries = tokio::fs::read_dir(dir)
+        .await
+        .with_context(|| format!("Failed to read {}", dir.display()))?;
+    let mut names = Vec::new();
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .with_context(|| format!("Failed to read {}", dir.display()))?
+    {
+        if let Ok(name) = entry.file_name().into_string() {
+            if name.starts_with("tomoru-") && name.ends_with(".json") {
+                names.push(name);
+            }
+        }
+    }
+
+    // Unix timestamps keep the same number of digits for centuries, so names sort by age
+    names.sort();
+    let excess = names.len().saturating_sub(keep);
+    for name in &names[..excess] {
+        let path = dir.join(name);
+        if let Err(e) = tokio::fs::remove_file(&path).await {
+            warn!("Failed to delete old snapshot {}: {}", path.display(), e);
+        }
+    }
+    Ok(())
+}
+
+/// Prints current request statistics every period until shutdown is requested
+///
+/// With a stats file, each snapshot is appended to it instead of being logged,
+/// and with a StatsD socket the aggregate gauges are sent to it too
+///
+/// With snapshots, the IP counts are also written to a new rotating snapshot file
+#[cfg(feature = "stats-printer")]
+async fn print_stats(
+    stats: Arc<AppState>,
+    period: Duration,
+    stats_file: Option<PathBuf>,
+    statsd: Option<Arc<UdpSocket>>,
+    snapshots: Option<Snapshots>,
+    shutdown: CancellationToken,
+) {
+    let mut interval = time::interval(period);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                stats.decay_counts();
+                let unix_seconds = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |elapsed| elapsed.as_secs());
+                stats.advance_history(unix_seconds);
+                match &stats_file {
+                    Some(path) => {
+                        if let Err(e) = append_stats(&stats, path).await {
+                            error!("Stats file error: {:#}", e);
+                        }
+                    }
+                    None => log_stats(&stats),
+                }
+                if let Some(socket) = &statsd {
+                    if let Err(e) = send_statsd(&stats, socket).await {
+                        warn!("StatsD error: {:#}", e);
+                    }
+                }
+                if let Some(snapshots) = &snapshots {
+                    if let Err(e) = rotate_snapshots(&stats, snapshots, unix_seconds).await {
+                        error!("Snapshot error: {:#}", e);
+                    }
+                }
+            }
+            _ = shutdown.cancelled() => break,
+        }
+    }
+}
+
+// Load persisted IP counts, treating a missing or corrupt file as empty
+fn load_state(path: &Path) -> PersistedCounts {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            info!("State file {} not found, starting empty", path.display());
+            return PersistedCounts::new();
+        }
+        Err(e) => {
+            warn!(
+                "Failed to read state file {}, starting empty: {}",
+                path.display(),
+                e
+            );
+            return PersistedCounts::new();
+        }
+    };
+
+    serde_json::from_str(&contents).unwrap_or_else(|e| {
+        warn!(
+            "Corrupt state file {}, starting empty: {}",
+            path.display(),
+            e
+        );
+        PersistedCounts::new()
+    })
+}
+
+// Replace the IP counts with those of the state file, keeping the
+// current ones if it can't be read
+async fn reload_state(stats: &AppState, path: &Path) -> Result<()> {
+    let contents = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let counts = serde_json::from_str(&contents)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    stats.replace_counts(counts);
+    Ok(())
+}
+
+// Read and parse the blocklist file
+async fn load_blocklist(path: &Path) -> Result<Blocklist> {
+    let contents = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    Blocklist::parse(&contents).with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+// Replace the blocklist with the file's, keeping the current one if it can't be read
+async fn reload_blocklist(stats: &AppState, path: &Path) -> Result<()> {
+    let blocklist = load_blocklist(path).await?;
+    info!(
+        "Reloaded blocklist {} with {} entries",
+        path.display(),
+        blocklist.len()
+    );
+    stats.replace_blocklist(blocklist);
+    Ok(())
+}
+
+// Serialize the IP counts in the state file format
+fn state_json(stats: &AppState) -> Result<Vec<u8>> {
+    serde_json::to_vec_pretty(&stats.to_persisted()).context("Failed to serialize state")
+}
+
+// Write IP counts to the state file
+async fn save_state(stats: &AppState, path: &Path) -> Result<()> {
+    let json = state_json(stats)?;
+
+    // Write to a temporary file first so a crash mid-write can't corrupt the previous state
+    let tmp_path = path.with_extension("tmp");
+    tokio::fs::write(&tmp_path, json)
+        .await
+        .with_context(|| format!("Failed to write {}", tmp_path.display()))?;
+    tokio::fs::rename(&tmp_path, path)
+        .await
+        .with_context(|| format!("Failed to replace {}", path.display()))?;
+
+    Ok(())
+}
+
+// Delay before a background task that panicked is started again
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+// Start the background task for printing statistics unless it's disabled
+#[cfg(feature = "stats-printer")]
+fn spawn_printer(
+    args: &Args,
+    stats: &Arc<AppState>,
+    statsd: Option<UdpSocket>,
+    shutdown: &CancellationToken,
+) -> Option<JoinHandle<()>> {
+    printer_enabled(args).then(|| {
+        let stats = stats.clone();
+        let period = Duration::from_secs(args.stats_interval);
+        let stats_file = args.stats_file.clone();
+        let statsd = statsd.map(Arc::new);
+        let snapshots = args.snapshot_dir.clone().map(|dir| Snapshots {
+            dir,
+            keep: args.snapshot_keep.get(),
+        });
+        tokio::spawn(supervise(
+            "Stats printer",
+            shutdown.clone(),
+            move |shutdown| {
+                print_stats(
+                    stats.clone(),
+                    period,
+                    stats_file.clone(),
+                    statsd.clone(),
+                    snapshots.clone(),
+                    shutdown,
+                )
+            },
+        ))
+    })
+}
+
+// Whether the stats printer runs, doing its housekeeping on every tick
+fn printer_enabled(args: &Args) -> bool {
+    cfg!(feature = "stats-printer") && !args.quiet && args.stats_interval > 0
+}
+
+// Flags given that only take effect on the stats printer's ticks
+fn printer_only_flags(args: &Args) -> Vec<&'static str> {
+    [
+        ("--decay", args.decay.is_some()),
+        ("--snapshot-dir", args.snapshot_dir.is_some()),
+        ("--statsd", args.statsd.is_some()),
+        ("--stats-file", args.stats_file.is_some()),
+        (
+            "--history-minutes",
+            args.history_minutes != DEFAULT_HISTORY_MINUTES,
+        ),
+        ("--color", args.color == ColorMode::Always),
+    ]
+    .into_iter()
+    .filter_map(|(flag, given)| given.then_some(flag))
+    .collect()
+}
+
+// Without the stats-printer feature there is no printer to start
+#[cfg(not(feature = "stats-printer"))]
+fn spawn_printer(
+    _args: &Args,
+    _stats: &Arc<AppState>,
+    _statsd: Option<UdpSocket>,
+    _shutdown: &CancellationToken,
+) -> Option<JoinHandle<()>> {
+    None
+}
+
+/// Runs the task, starting it again after RESTART_DELAY whenever it panics,
+/// until it returns or shutdown is requested
+async fn supervise<F, Fut>(name: &'static str, shutdown: CancellationToken, mut task: F)
+where
+    F: FnMut(CancellationToken) -> Fut,
+    Fut: std::future::Future<Output = ()> + Send + 'static,
+{
+    loop {
+        match tokio::spawn(task(shutdown.clone())).await {
+            Ok(()) => return,
+            Err(e) => error!("{} failed, restarting: {}", name, e),
+        }
+        tokio::select! {
+            _ = time::sleep(RESTART_DELAY) => {}
+            _ = shutdown.cancelled() => return,
+        }
+    }
+}
+
+/// Drops expired recent request buckets and faded rates every period until
+/// shutdown is requested, whether or not the stats printer runs
+async fn prune_stats(stats: Arc<AppState>, period: Duration, shutdown: CancellationToken) {
+    let mut interval = time::interval(period);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let now = Instant::now();
+                stats.prune_recent(now);
+                stats.prune_rates(now);
+            }
+            _ = shutdown.cancelled() => break,
+        }
+    }
+}
+
+/// Saves IP counts to the state file periodically until shutdown is requested
+async fn persist_state(
+    stats: Arc<AppState>,
+    path: PathBuf,
+    period: Duration,
+    shutdown: CancellationToken,
+) {
+    let mut interval = time::interval(period);
+    // The first tick completes immediately and there is nothing new to save yet
+    interval.tick().await;
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                if let Err(e) = save_state(&stats, &path).await {
+                    error!("State persistence error: {:#}", e);
+                }
+            }
+            _ = shutdown.cancelled() => break,
+        }
+    }
+}
+
+/// Reloads the IP counts from a primary's state file periodically until shutdown is requested
+async fn reload_replica(
+    stats: Arc<AppState>,
+    path: PathBuf,
+    period: Duration,
+    shutdown: CancellationToken,
+) {
+    let mut interval = time::interval(period);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                if let Err(e) = reload_state(&stats, &path).await {
+                    warn!("Replica reload error: {:#}", e);
+                }
+            }
+            _ = shutdown.cancelled() => break,
+        }
+    }
+}
+
+/// Reloads the blocklist on every SIGHUP until shutdown is requested
+#[cfg(unix)]
+async fn reload_blocklist_on_hangup(
+    stats: Arc<AppState>,
+    path: PathBuf,
+    shutdown: CancellationToken,
+) {
+    let mut hangup = match signal::unix::signal(signal::unix::SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!("Failed to listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {
+                if let Err(e) = reload_blocklist(&stats, &path).await {
+                    warn!("Blocklist reload error: {:#}", e);
+                }
+            }
+            _ = shutdown.cancelled() => break,
+        }
+    }
+}
+
+// Look up the hostnames of the busiest IPs that haven't been looked up yet
+// Each lookup runs on the blocking thread pool and is bounded by PTR_TIMEOUT,
+// and failures are cached as well, so they aren't retried on every round
+async fn resolve_top_hostnames(stats: &AppState, lookup: fn(IpAddr) -> Option<String>) {
+    let pending = stats
+        .get_top_ip_counts(Some(PTR_TOP_IPS), SortOrder::CountDesc)
+        .into_iter()
+        .map(|(ip, _)| ip)
+        .filter(|ip| !stats.hostnames.contains_key(ip));
+    let lookups = pending.map(|ip| async move {
+        let hostname =
+            time::timeout(PTR_TIMEOUT, tokio::task::spawn_blocking(move || lookup(ip))).await;
+        (ip, hostname.ok().and_then(Result::ok).flatten())
+    });
+
+    for (ip, hostname) in futures_util::future::join_all(lookups).await {
+        stats.hostnames.insert(ip, hostname);
+    }
+}
+
+/// Looks up the hostnames of new busy IPs periodically until shutdown is requested
+async fn resolve_hostnames(stats: Arc<AppState>, period: Duration, shutdown: CancellationToken) {
+    let mut interval = time::interval(period);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => resolve_top_hostnames(&stats, reverse_lookup).await,
+            _ = shutdown.cancelled() => break,
+        }
+    }
+}
+
+// Load the TLS configuration if both a certificate and a key are given
+async fn load_tls_config(cert: Option<&Path>, key: Option<&Path>) -> Result<Option<RustlsConfig>> {
+    match (cert, key) {
+        (Some(cert), Some(key)) => RustlsConfig::from_pem_file(cert, key)
+            .await
+            .map(Some)
+            .with_context(|| {
+                format!(
+                    "Failed to load TLS certificate {} and key {}",
+                    cert.display(),
+                    key.display()
+                )
+            }),
+        (None, None) => Ok(None),
+        _ => bail!("--tls-cert and --tls-key must be provided together"),
+    }
+}
+
+// Set up the application routes and middleware
+// With --admin-bind, the stats and admin routes are left to build_admin_router
+fn build_router(args: &Args, stats: Arc<AppState>) -> Router {
+    let ping_response = args.ping_response.clone();
+    let mut routes = Router::new()
+        .route(
+            "/ping",
+            get(move |state| ping(state, ping_response.clone())),
+        )
+        .fallback(not_found);
+    if args.admin_bind.is_none() {
+        routes = routes.merge(admin_routes(args, &stats));
+    }
+
+    wrap_routes(routes, args, stats)
+}
+
+// Set up the router of the --admin-bind listener
+// Its requests aren't counted, so checking the stats doesn't skew them
+fn build_admin_router(args: &Args, stats: Arc<AppState>) -> Router {
+    admin_routes(args, &stats)
+        .fallback(not_found)
+        .layer(CatchPanicLayer::custom(handle_panic))
+        .layer(RequestBodyLimitLayer::new(args.max_body_bytes))
+        .route("/healthz", get(healthz))
+        .route("/version", get(version))
+        .layer(map_response(json_errors))
+        .layer(from_fn(request_id_middleware))
+        .with_state(stats)
+}
+
+// Stats routes along with the enabled admin endpoints
+fn admin_routes(args: &Args, stats: &AppState) -> Router<Arc<AppState>> {
+    // Live streams push updates as often as the printer, even when it's disabled
+    let stream_period = Duration::from_secs(args.stats_interval.max(1));
+    let mut stats_routes = Router::new()
+        .route("/stats", get(stats_text))
+        .route("/stats.json", get(stats_json))
+        .route("/stats.csv", get(stats_csv))
+        .route("/stats/detailed", get(stats_detailed))
+        .route("/stats/summary", get(stats_summary))
+        .route("/stats/methods", get(stats_methods))
+        .route("/stats/status", get(stats_status))
+        .route("/stats/bandwidth", get(stats_bandwidth))
+        .route("/stats/latency", get(stats_latency))
+        .route(
+            "/stats/ws",
+            get(move |ws, state| stats_ws(ws, state, stream_period)),
+        )
+        .route(
+            "/stats/sse",
+            get(move |state| stats_sse(state, stream_period)),
+        )
+        .route("/stats/recent", get(stats_recent))
+        .route("/stats/active", get(stats_active))
+        .route("/stats/rates", get(stats_rates))
+        .route("/stats/timeseries", get(stats_timeseries))
+        .route("/stats/ip/{addr}", get(stats_ip))
+        .route("/stats/subnets", get(stats_subnets))
+        .route("/stats/scanners", get(stats_scanners))
+        .route("/stats/mark", post(stats_mark))
+        .route("/stats/since", get(stats_since))
+        .route("/stats/snapshot/{name}", post(stats_snapshot))
+        .route("/stats/diff", get(stats_diff))
+        .route("/stats/export", get(stats_export))
+        .route("/stats/histogram", get(stats_histogram))
+        .route("/metrics", get(metrics));
+    if stats.geoip.is_some() {
+        stats_routes = stats_routes.route("/stats/countries", get(stats_countries));
+    }
+    // Comparing against the encoded credentials saves decoding every request's
+    let auth = match (&args.stats_user, &args.stats_pass) {
+        (Some(user), Some(pass)) => {
+            let credentials: Arc<str> = BASE64.encode(format!("{}:{}", user, pass)).into();
+            Some(from_fn_with_state(credentials, basic_auth))
+        }
+        _ => None,
+    };
+    if let Some(auth) = &auth {
+        stats_routes = stats_routes.layer(auth.clone());
+    }
+    // Stats responses grow with the number of IPs, while /ping is tiny
+    stats_routes = stats_routes.layer(CompressionLayer::new());
+    // Only the stats routes are readable cross-origin, /ping and /reset stay locked down
+    if let Some(cors) = cors_layer(args) {
+        stats_routes = stats_routes.layer(cors);
+    }
+
+    let mut routes = stats_routes;
+    if args.enable_reset {
+        let mut reset_routes = Router::new()
+            .route("/reset", post(reset))
+            .route("/stats/reset-ip/{addr}", post(reset_ip));
+        // Clearing the stats needs the same credentials as reading them
+        if let Some(auth) = auth {
+            reset_routes = reset_routes.layer(auth);
+        }
+        routes = routes.merge(reset_routes);
+    }
+    if let Some(admin_token) = &args.admin_token {
+        let admin_token = admin_token.clone();
+        routes = routes.route(
+            "/shutdown",
+            post(move |state, headers| shutdown(state, headers, admin_token.clone())),
+        );
+    }
+    routes
+}
+
+// Apply the request handling and counting layers to the routes
+//
+// The counter layer must stay the outermost one: layers added before it only
+// see requests that were already counted, so whatever they reject, like
+// requests that panic, time out or fail auth, still shows up in the stats.
+// Layers added after it would hide exactly the abusive traffic they turn away.
+fn wrap_routes(mut routes: Router<Arc<AppState>>, args: &Args, stats: Arc<AppState>) -> Router {
+    routes = routes.layer(CatchPanicLayer::custom(handle_panic));
+    if let Some(timeout) = args.request_timeout {
+        routes = routes.layer(TimeoutLayer::with_status_code(
+            StatusCode::REQUEST_TIMEOUT,
+            Duration::from_secs(timeout),
+        ));
+    }
+    routes = routes.layer(RequestBodyLimitLayer::new(args.max_body_bytes));
+    // Queued requests are counted on arrival, not once they get a slot
+    if let Some(limit) = concurrency_limit(args) {
+        routes = routes.layer(limit);
+    }
+
+    routes
+        .layer(from_fn_with_state(stats.clone(), counter_middleware))
+        // Routes added after the counter layer aren't counted, nor limited
+        .route("/healthz", get(healthz))
+        .route("/version", get(version))
+        // These reject nothing, so they can wrap the counter layer to cover every route
+        .layer(map_response(json_errors))
+        .layer(from_fn(request_id_middleware))
+        .with_state(stats)
+}
+
+// CORS layer allowing the configured origins to read the stats, if any are
+fn cors_layer(args: &Args) -> Option<CorsLayer> {
+    if args.cors_origin.is_empty() {
+        return None;
+    }
+    let origin = if args.cors_origin.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(args.cors_origin.clone())
+    };
+    Some(
+        CorsLayer::new()
+            .allow_origin(origin)
+            .allow_methods([Method::GET]),
+    )
+}
+
+// Log a handler panic and respond with 500 instead of dropping the connection
+fn handle_panic(panic: Box<dyn std::any::Any + Send>) -> Response {
+    let message = panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic");
+    error!("Handler panicked: {}", message);
+    ApiError::Internal.into_response()
+}
+
+// Limit shared by all counted routes on how many requests are handled at once
+// Requests over the limit wait for a slot rather than being rejected
+fn concurrency_limit(args: &Args) -> Option<GlobalConcurrencyLimitLayer> {
+    args.max_connections
+        .map(|max| GlobalConcurrencyLimitLayer::new(max.get()))
+}
+
+/// Completes once the process receives Ctrl-C or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = signal::ctrl_c().await {
+            error!("Failed to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// Requests a graceful shutdown once the duration has elapsed,
+/// unless one was requested before
+async fn shutdown_after(duration: Duration, stats: Arc<AppState>) {
+    tokio::select! {
+        _ = time::sleep(duration) => {
+            info!("Ran for {}s, shutting down...", duration.as_secs());
+            stats.drain();
+        }
+        _ = stats.shutdown.cancelled() => {}
+    }
+}
+
+// Parse a decay factor, which must be in (0, 1]
+fn parse_decay(value: &str) -> Result<f64, String> {
+    let factor: f64 = value.parse().map_err(|e| format!("{}", e))?;
+    if !(factor > 0.0 && factor <= 1.0) {
+        return Err(format!("decay factor must be in (0, 1], got {}", factor));
+    }
+    Ok(factor)
+}
+
+// Parse a port range given as START-END
+fn parse_port_range(value: &str) -> Result<RangeInclusive<u16>, String> {
+    let (start, end) = value
+        .split_once('-')
+        .ok_or_else(|| format!("expected START-END, got {}", value))?;
+    let start: u16 = start
+        .parse()
+        .map_err(|e| format!("invalid start port: {}", e))?;
+    let end: u16 = end
+        .parse()
+        .map_err(|e| format!("invalid end port: {}", e))?;
+    if start > end {
+        return Err(format!("start port {} is after end port {}", start, end));
+    }
+    Ok(start..=end)
+}
+
+// Bind to the address, or to the first free port of the range on its IP if one is given
+async fn bind_tcp(
+    bind_addr: SocketAddr,
+    port_range: Option<RangeInclusive<u16>>,
+) -> Result<tokio::net::TcpListener> {
+    let Some(port_range) = port_range else {
+        return tokio::net::TcpListener::bind(bind_addr)
+            .await
+            .with_context(|| format!("Failed to bind to {}", bind_addr));
+    };
+
+    for port in port_range.clone() {
+        let addr = SocketAddr::new(bind_addr.ip(), port);
+        match tokio::net::TcpListener::bind(addr).await {
+            Ok(listener) => {
+                info!(
+                    "Bound to port {} of range {}-{}",
+                    port,
+                    port_range.start(),
+                    port_range.end()
+                );
+                return Ok(listener);
+            }
+            Err(e) => warn!("Failed to bind to {}: {}", addr, e),
+        }
+    }
+    bail!(
+        "No free port in range {}-{} on {}",
+        port_range.start(),
+        port_range.end(),
+        bind_addr.ip()
+    )
+}
+
+// Validate the arguments and load the files they refer to,
+// without binding, spawning or writing anything
+async fn load_config(args: &Args) -> Result<Config> {
+    let bind_addrs = args
+        .bind
+        .iter()
+        .map(|bind| {
+            bind.parse()
+                .with_context(|| format!("Invalid bind address: {}", bind))
+        })
+        .collect::<Result<Vec<SocketAddr>>>()?;
+    let tls_config = load_tls_config(args.tls_cert.as_deref(), args.tls_key.as_deref()).await?;
+    let geoip = args.geoip_db.as_deref().map(load_geoip_db).transpose()?;
+    let blocklist = match &args.blocklist {
+        Some(path) => load_blocklist(path).await?,
+        None => Blocklist::default(),
+    };
+
+    // These files may not exist yet, but must be creatable
+    for path in [&args.state_file, &args.stats_file].into_iter().flatten() {
+        check_parent_dir(path)?;
+    }
+    let statsd = match &args.statsd {
+        Some(addr) => Some(resolve_statsd(addr).await?),
+        None => None,
+    };
+
+    Ok(Config {
+        bind_addrs,
+        tls_config,
+        geoip,
+        blocklist,
+        statsd,
+    })
+}
+
+// Log the settings in effect as a single structured event
+fn log_config(args: &Args, bind_addrs: &[SocketAddr], tls: bool) {
+    let listen = match &args.uds {
+        Some(path) => format!("unix:{}", path.display()),
+        None => {
+            let addrs: Vec<String> = bind_addrs
+                .iter()
+                .map(|addr| match &args.port_range {
+                    Some(range) => format!("{}:{}-{}", addr.ip(), range.start(), range.end()),
+                    None => addr.to_string(),
+                })
+                .collect();
+            addrs.join(",")
+        }
+    };
+    let stats_interval = if args.quiet { 0 } else { args.stats_interval };
+
+    info!(
+        %listen,
+        tls,
+        stats_interval,
+        state_file = ?args.state_file,
+        replica = ?args.replica,
+        admin_bind = ?args.admin_bind,
+        http2 = args.http2,
+        trust_proxy = args.trust_proxy,
+        real_ip_header = ?args.real_ip_header,
+        dedup_header = ?args.dedup_header,
+        rate_limit = ?args.rate_limit.map(|limit| format!("{}/{}s", limit, args.rate_window)),
+        ban_threshold = ?args.ban_threshold,
+        blocklist = ?args.blocklist,
+        alert_threshold = ?args.alert_threshold,
+        max_ips = ?args.max_ips,
+        max_connections = ?args.max_connections,
+        max_conns_per_ip = ?args.max_conns_per_ip,
+        tcp_nodelay = args.tcp_nodelay,
+        tcp_keepalive = ?args.tcp_keepalive,
+        request_timeout = ?args.request_timeout,
+        max_body_bytes = args.max_body_bytes,
+        geoip = args.geoip_db.is_some(),
+        statsd = ?args.statsd,
+        snapshot_dir = ?args.snapshot_dir,
+        resolve_ptr = args.resolve_ptr,
+        run_for = ?args.run_for,
+        decay = ?args.decay,
+        ignored_ips = args.ignore_ips.len(),
+        anonymize = args.anonymize,
+        count_path_prefixes = ?args.count_path_prefixes,
+        reset_enabled = args.enable_reset,
+        shutdown_enabled = args.admin_token.is_some(),
+        stats_auth = args.stats_user.is_some(),
+        access_log = args.access_log,
+        log_format = ?args.log_format,
+        color = use_color(args),
+        "Starting with configuration"
+    );
+}
+
+// Make sure the directory the file would be created in exists
+fn check_parent_dir(path: &Path) -> Result<()> {
+    let parent = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    if !parent.is_dir() {
+        bail!("Directory of {} does not exist", path.display());
+    }
+    Ok(())
+}
+
+/// Serves the router on every listener at once until shutdown,
+/// stopping at the first listener that fails
+async fn serve_listeners(
+    app: Router,
+    listeners: Vec<tokio::net::TcpListener>,
+    tls_config: Option<RustlsConfig>,
+    connection_limiter: Option<Arc<ConnectionLimiter>>,
+    tcp_options: TcpOptions,
+    http2: bool,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let servers = listeners.into_iter().map(|listener| {
+        serve_tcp(
+            app.clone(),
+            listener,
+            tls_config.clone(),
+            connection_limiter.clone(),
+            tcp_options,
+            http2,
+            shutdown.clone(),
+        )
+    });
+    futures_util::future::try_join_all(servers).await?;
+    Ok(())
+}
+
+// Connection preface of HTTP/2 with prior knowledge (h2c)
+const H2C_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// Stream holding its IP's connection slot, if limited, until it's closed
+struct LimitedStream<S> {
+    stream: S,
+    _guard: Option<ConnectionGuard>,
+    // Number of h2c preface bytes read so far while HTTP/2 is rejected,
+    // None once the connection turned out to be something else
+    h2c_matched: Option<usize>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for LimitedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let start = buf.filled().len();
+        task::ready!(Pin::new(&mut self.stream).poll_read(cx, buf))?;
+
+        // The preface may arrive in pieces, so keep matching across reads
+        if let Some(matched) = self.h2c_matched {
+            let read = &buf.filled()[start..];
+            let len = read.len().min(H2C_PREFACE.len() - matched);
+            self.h2c_matched = if read.is_empty() || read[..len] != H2C_PREFACE[matched..][..len] {
+                None
+            } else if matched + len == H2C_PREFACE.len() {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "HTTP/2 over plain HTTP is disabled without --http2",
+                )));
+            } else {
+                Some(matched + len)
+            };
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for LimitedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+// Socket options applied to every accepted TCP connection
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct TcpOptions {
+    nodelay: bool,
+    keepalive: Option<Duration>,
+}
+
+impl TcpOptions {
+    fn from_args(args: &Args) -> Self {
+        Self {
+            nodelay: args.tcp_nodelay,
+            keepalive: args.tcp_keepalive.map(Duration::from_secs),
+        }
+    }
+
+    // Set the options on the socket, leaving the OS defaults of unset ones
+    fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        if self.nodelay {
+            stream.set_nodelay(true)?;
+        }
+        if let Some(idle) = self.keepalive {
+            let keepalive = socket2::TcpKeepalive::new().with_time(idle);
+            socket2::SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+        }
+        Ok(())
+    }
+}
+
+// Acceptor tuning accepted sockets and closing connections
+// from IPs that already have too many open, or that start HTTP/2 unasked
+#[derive(Clone)]
+struct TcpAcceptor {
+    limiter: Option<Arc<ConnectionLimiter>>,
+    options: TcpOptions,
+    // Whether to close plain connections opening with the h2c preface
+    reject_h2c: bool,
+}
+
+impl TcpAcceptor {
+    fn limit(&self, stream: TcpStream) -> io::Result<LimitedStream<TcpStream>> {
+        self.options.apply(&stream)?;
+        let guard = match &self.limiter {
+            Some(limiter) => {
+                let ip = stream.peer_addr()?.ip();
+                let guard = limiter.acquire(ip).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::ConnectionRefused, "Too many connections")
+                })?;
+                Some(guard)
+            }
+            None => None,
+        };
+        Ok(LimitedStream {
+            stream,
+            _guard: guard,
+            h2c_matched: self.reject_h2c.then_some(0),
+        })
+    }
+}
+
+impl<S> Accept<TcpStream, S> for TcpAcceptor {
+    type Stream = LimitedStream<TcpStream>;
+    type Service = S;
+    type Future = Ready<io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: TcpStream, service: S) -> Self::Future {
+        std::future::ready(self.limit(stream).map(|stream| (stream, service)))
+    }
+}
+
+/// Serves the router over TCP, with HTTPS if a TLS config is given, until shutdown
+async fn serve_tcp(
+    app: Router,
+    listener: tokio::net::TcpListener,
+    tls_config: Option<RustlsConfig>,
+    connection_limiter: Option<Arc<ConnectionLimiter>>,
+    tcp_options: TcpOptions,
+    http2: bool,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let local_addr = listener
+        .local_addr()
+        .context("Failed to get local address")?;
+
+    let handle = axum_server::Handle::<SocketAddr>::new();
+    tokio::spawn({
+        let handle = handle.clone();
+        async move {
+            shutdown.cancelled().await;
+            handle.graceful_shutdown(None);
+        }
+    });
+
+    // Excess connections are dropped before the TLS handshake, if any
+    let server = axum_server::Server::from_listener(listener).handle(handle);
+    // Over TLS, HTTP/2 is only ever negotiated via ALPN
+    let acceptor = TcpAcceptor {
+        limiter: connection_limiter,
+        options: tcp_options,
+        reject_h2c: tls_config.is_none() && !http2,
+    };
+    let make_service = app.into_make_service_with_connect_info::<SocketAddr>();
+    match tls_config {
+        Some(tls_config) => {
+            info!("Server running on https://{}", local_addr);
+
+            server
+                .acceptor(RustlsAcceptor::new(tls_config).acceptor(acceptor))
+                .serve(make_service)
+                .await
+        }
+        None => {
+            info!("Server running on http://{}", local_addr);
+
+            // Plain connections stay HTTP/1 unless --http2 allows the h2c preface
+            // The acceptor rejects it rather than http1_only(), which also disables upgrades
+            server.acceptor(acceptor).serve(make_service).await
+        }
+    }
+    .context("Server error")
+}
+
+/// Serves the router on a Unix domain socket until shutdown, then removes the socket file
+#[cfg(unix)]
+async fn serve_uds(app: Router, path: &Path, shutdown: CancellationToken) -> Result<()> {
+    let listener = tokio::net::UnixListener::bind(path)
+        .with_context(|| format!("Failed to bind to {}", path.display()))?;
+
+    info!("Server running on unix:{}", path.display());
+
+    let served = axum::serve(listener, app.into_make_service())
+        .with_graceful_shutdown(shutdown.cancelled_owned())
+        .await
+        .context("Server error");
+
+    if let Err(e) = tokio::fs::remove_file(path).await {
+        warn!("Failed to remove socket file {}: {}", path.display(), e);
+    }
+    served
+}
+
+#[cfg(not(unix))]
+async fn serve_uds(_app: Router, _path: &Path, _shutdown: CancellationToken) -> Result<()> {
+    bail!("Unix domain sockets are not supported on this platform")
+}
+
+// Build the log subscriber for the format, writing to `writer`
+fn log_subscriber<W>(
+    format: LogFormat,
+    filter: EnvFilter,
+    writer: W,
+) -> Box<dyn tracing::Subscriber + Send + Sync>
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer);
+    match format {
+        LogFormat::Text => Box::new(builder.finish()),
+        LogFormat::Json => Box::new(builder.json().finish()),
+    }
+}
+
+/// Runs the service with the command line options until it shuts down
+pub async fn run() -> Result<()> {
+    let args = Args::parse();
+
+    // Log at info level unless overridden via RUST_LOG
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    log_subscriber(args.log_format, filter, io::stdout).init();
+
+    let Config {
+        bind_addrs,
+        tls_config,
+        geoip,
+        blocklist,
+        statsd,
+    } = load_config(&args).await?;
+    log_config(&args, &bind_addrs, tls_config.is_some());
+    if args.dry_run {
+        info!("Configuration is valid");
+        return Ok(());
+    }
+    let statsd = match statsd {
+        Some(target) => Some(connect_statsd(target).await?),
+        None => None,
+    };
+
+    // Initialize shared application state
+    let base = AppState::with_capacity(args.expected_ips.unwrap_or(0));
+    let stats: Arc<AppState> = Arc::new(AppState {
+        ip_counts: base.ip_counts.limited_to(args.max_ips),
+        bytes_in: base.bytes_in.limited_to(args.max_ips),
+        bytes_out: base.bytes_out.limited_to(args.max_ips),
+        trust_proxy: args.trust_proxy,
+        real_ip_header: args.real_ip_header.clone(),
+        rate_limiter: args
+            .rate_limit
+            .map(|limit| RateLimiter::new(limit, Duration::from_secs(args.rate_window))),
+        deduplicator: args.dedup_header.clone().map(|header| {
+            Deduplicator::new(header, Duration::from_secs(args.dedup_ttl), MAX_DEDUP_KEYS)
+        }),
+        connection_limiter: args
+            .max_conns_per_ip
+            .map(|limit| Arc::new(ConnectionLimiter::new(limit))),
+        ban_threshold: args.ban_threshold,
+        blocklist: RwLock::new(blocklist),
+        alert_threshold: args.alert_threshold,
+        access_log: args.access_log,
+        ignored_ips: args.ignore_ips.iter().copied().collect(),
+        anonymize: args.anonymize,
+        count_path_prefixes: args.count_path_prefixes.clone(),
+        decay: args.decay,
+        replica: args.replica.is_some(),
+        stats_label: Some(args.stats_label.clone()),
+        stats_percent: args.stats_percent,
+        stats_top: args.stats_top,
+        color: use_color(&args),
+        history_minutes: args.history_minutes,
+        started: Some(Instant::now()),
+        geoip,
+        ..base
+    });
+
+    let shutdown = stats.shutdown.clone();
+    tokio::spawn({
+        let stats = stats.clone();
+        async move {
+            shutdown_signal().await;
+            info!("Shutting down...");
+            stats.drain();
+        }
+    });
+    if let Some(run_for) = args.run_for {
+        tokio::spawn(shutdown_after(Duration::from_secs(run_for), stats.clone()));
+    }
+
+    // Restore previously persisted counts and keep saving them
+    let persister = args.state_file.as_ref().map(|path| {
+        stats.restore(load_state(path));
+        tokio::spawn(persist_state(
+            stats.clone(),
+            path.clone(),
+            Duration::from_secs(args.persist_interval),
+            shutdown.clone(),
+        ))
+    });
+
+    // Follow the primary's counts instead of counting
+    if let Some(path) = &args.replica {
+        tokio::spawn(reload_replica(
+            stats.clone(),
+            path.clone(),
+            Duration::from_secs(args.persist_interval),
+            shutdown.clone(),
+        ));
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = &args.blocklist {
+        tokio::spawn(reload_blocklist_on_hangup(
+            stats.clone(),
+            path.clone(),
+            shutdown.clone(),
+        ));
+    }
+
+    tokio::spawn(supervise("Stats pruner", shutdown.clone(), {
+        let stats = stats.clone();
+        move |shutdown| prune_stats(stats.clone(), PRUNE_INTERVAL, shutdown)
+    }));
+
+    if args.resolve_ptr {
+        tokio::spawn(resolve_hostnames(
+            stats.clone(),
+            PTR_REFRESH_INTERVAL,
+            shutdown.clone(),
+        ));
+    }
+
+    // Fail early rather than on every tick if the stats file can't be opened
+    if let Some(path) = &args.stats_file {
+        open_stats_file(path).await?;
+    }
+    if !printer_enabled(&args) {
+        for flag in printer_only_flags(&args) {
+            warn!(
+                "{} has no effect without the stats printer (disabled or not compiled in)",
+                flag
+            );
+        }
+    }
+    let printer = spawn_printer(&args, &stats, statsd, &shutdown);
+
+    let app = build_router(&args, stats.clone());
+
+    // Bind every address before serving any, so a busy one fails startup
+    let mut listeners = Vec::with_capacity(bind_addrs.len());
+    if args.uds.is_none() {
+        for bind_addr in bind_addrs {
+            listeners.push(bind_tcp(bind_addr, args.port_range.clone()).await?);
+        }
+    }
+    let admin_listener = match args.admin_bind {
+        Some(admin_addr) => Some(bind_tcp(admin_addr, None).await?),
+        None => None,
+    };
+
+    // In-flight requests are allowed to complete once shutdown is requested
+    let public = async {
+        match &args.uds {
+            Some(path) => serve_uds(app, path, shutdown.clone()).await,
+            None => {
+                serve_listeners(
+                    app,
+                    listeners,
+                    tls_config.clone(),
+                    stats.connection_limiter.clone(),
+                    TcpOptions::from_args(&args),
+                    args.http2,
+                    shutdown.clone(),
+                )
+                .await
+            }
+        }
+    };
+    let admin = async {
+        match admin_listener {
+            Some(listener) => {
+                let admin_app = build_admin_router(&args, stats.clone());
+                serve_tcp(
+                    admin_app,
+                    listener,
+                    tls_config.clone(),
+                    None,
+                    TcpOptions::from_args(&args),
+                    args.http2,
+                    shutdown.clone(),
+                )
+                .await
+            }
+            None => Ok(()),
+        }
+    };
+    let served = tokio::try_join!(public, admin).map(|_| ());
+
+    // Stop the printer even if the server exited on its own
+    shutdown.cancel();
+    if let Some(printer) = printer {
+        if let Err(e) = printer.await {
+            error!("Stats printer error: {}", e);
+        }
+    }
+
+    // Save the final counts so they survive the restart
+    if let (Some(persister), Some(path)) = (persister, &args.state_file) {
+        if let Err(e) = persister.await {
+            error!("State persister error: {}", e);
+        }
+        if let Err(e) = save_state(&stats, path).await {
+            error!("State persistence error: {:#}", e);
+        }
+    }
+
+    // Log the final snapshot so the last counts are not lost,
+    // or print the IP counts on their own for scripts sampling a fixed run
+    if args.run_for.is_some() {
+        print!("{}", stats.format_ip_stats());
+    } else {
+        log_stats(&stats);
+    }
+
+    served
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::connect_info::MockConnectInfo;
+    use tower::ServiceExt;
+
+    // Build the router as run() does, with requests coming from 127.0.0.1
+    fn test_router(args: &[&str], state: Arc<AppState>) -> Router {
+        let args =
+            Args::try_parse_from(std::iter::once("tomoru").chain(args.iter().copied())).unwrap();
+        build_router(&args, state).layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000))))
+    }
+
+    // Serve the router on a free local port until shutdown
+    async fn spawn_server(
+        app: Router,
+        shutdown: &CancellationToken,
+    ) -> (SocketAddr, JoinHandle<io::Result<()>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = shutdown.clone();
+        let server = tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(shutdown.cancelled_owned())
+            .await
+        });
+        (addr, server)
+    }
+
+    // Build a /ping request arriving from the given peer address
+    fn ping_from(peer: [u8; 4]) -> Request {
+        let mut request = Request::get("/ping").body(Body::empty()).unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from((peer, 4000))));
+        request
+    }
+
+    #[tokio::test]
+    async fn router_counts_requests_per_peer() {
+        let state = Arc::new(AppState::default());
+        let args = Args::try_parse_from(["tomoru"]).unwrap();
+        let app = build_router(&args, state.clone());
+
+        for peer in [[10, 0, 0, 1], [10, 0, 0, 2], [10, 0, 0, 1]] {
+            let response = app.clone().oneshot(ping_from(peer)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(body, "pong");
+        }
+
+        assert_eq!(
+            state.get_sorted_ip_counts(),
+            vec![
+                (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 2),
+                (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 1),
+            ]
+        );
+        assert_eq!(state.get_sorted_path_counts(), vec![("/ping".into(), 3)]);
+    }
+
+    #[test]
+    fn increment_ip_count() {
+        let state = AppState::default();
+        let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+
+        state.increment_ip_count(ip);
+        assert_eq!(state.get_sorted_ip_counts(), vec![(ip, 1)]);
+
+        state.increment_ip_count(ip);
+        assert_eq!(state.get_sorted_ip_counts(), vec![(ip, 2)]);
+    }
+
+    #[test]
+    fn increment_path_count() {
+        let state = AppState::default();
+
+        state.increment_path_count("/ping");
+        state.increment_path_count("/ping");
+        state.increment_path_count("/stats");
+
+        assert_eq!(
+            state.get_sorted_path_counts(),
+            vec![("/ping".to_string(), 2), ("/stats".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn path_counts_are_limited() {
+        let state = AppState::with_capacity(0);
+        state.increment_path_count("/ping");
+        state.increment_path_count("/ping");
+
+        for index in 0..MAX_TRACKED_PATHS {
+            state.increment_path_count(&format!("/scan/{}", index));
+        }
+        assert!(state.path_counts.len() <= MAX_TRACKED_PATHS);
+        assert_eq!(state.path_counts.get("/ping"), Some(2));
+    }
+
+    #[test]
+    fn format_path_stats() {
+        let state = AppState::default();
+
+        state.increment_path_count("/ping");
+
+        assert_eq!(state.format_path_stats(), "Paths:\n  /ping: 1\n");
+    }
+
+    #[test]
+    fn format_method_stats() {
+        let state = AppState::default();
+
+        state.increment_method_count(&Method::GET);
+        state.increment_method_count(&Method::GET);
+        state.increment_method_count(&Method::POST);
+
+        assert_eq!(
+            state.format_method_stats(),
+            "Methods:\n  GET: 2\n  POST: 1\n"
+        );
+    }
+
+    #[test]
+    fn format_status_stats() {
+        let state = AppState::default();
+
+        state.increment_status_count(StatusCode::OK);
+        state.increment_status_count(StatusCode::NOT_FOUND);
+        state.increment_status_count(StatusCode::OK);
+
+        assert_eq!(
+            state.format_status_stats(),
+            "Statuses:\n  200: 2\n  404: 1\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn middleware_counts_statuses_once_per_request() {
+        let state = Arc::new(AppState {
+            ban_threshold: Some(1),
+            ..Default::default()
+        });
+        let app = test_router(&[], state.clone());
+
+        for uri in ["/ping", "/missing"] {
+            let request = Request::get(uri).body(Body::empty()).unwrap();
+            app.clone().oneshot(request).await.unwrap();
+        }
+
+        // The second request is rejected before reaching the router
+        let mut statuses = state.status_counts.snapshot();
+        statuses.sort();
+        assert_eq!(statuses, vec![(200, 1), (403, 1)]);
+        assert_eq!(state.total_requests(), 2);
+    }
+
+    #[tokio::test]
+    async fn middleware_records_bandwidth() {
+        let state = Arc::new(AppState::default());
+        let app = test_router(&["--enable-reset"], state.clone());
+
+        let request = Request::get("/ping").body(Body::empty()).unwrap();
+        app.clone().oneshot(request).await.unwrap();
+        let request = Request::post("/reset")
+            .header(header::CONTENT_LENGTH, "3")
+            .body(Body::from("abc"))
+            .unwrap();
+        app.oneshot(request).await.unwrap();
+
+        // Reset runs before its own response is counted
+        assert_eq!(
+            state.get_bandwidth(),
+            vec![IpBandwidth {
+                ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                bytes_in: 3,
+                bytes_out: "reset".len() as u64,
+            }]
+        );
+    }
+
+    #[test]
+    fn get_bandwidth() {
+        let state = AppState::default();
+        let ip1 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let ip2 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+        state.record_bandwidth(ip1, 10, 0);
+        state.record_bandwidth(ip2, 0, 100);
+        state.record_bandwidth(ip1, 0, 5);
+
+        assert_eq!(
+            state.get_bandwidth(),
+            vec![
+                IpBandwidth {
+                    ip: ip2,
+                    bytes_in: 0,
+                    bytes_out: 100
+                },
+                IpBandwidth {
+                    ip: ip1,
+                    bytes_in: 10,
+                    bytes_out: 5
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn latency_percentiles() {
+        let state = AppState::default();
+        for ms in 1..=100 {
+            state.record_latency("/ping", Duration::from_millis(ms));
+        }
+        state.record_latency("/stats", Duration::from_secs(2 * 60 * 60));
+
+        // Values are within the histogram's 1% error
+        let latencies = state.get_latency_percentiles();
+        let ping = &latencies[0];
+        assert_eq!((ping.path.as_str(), ping.count), ("/ping", 100));
+        assert!(ping.p50_us.abs_diff(50_000) <= 500);
+        assert!(ping.p95_us.abs_diff(95_000) <= 950);
+        assert!(ping.p99_us.abs_diff(99_000) <= 990);
+
+        // Latencies beyond the tracked range are capped rather than dropped
+        let stats = &latencies[1];
+        assert_eq!((stats.path.as_str(), stats.count), ("/stats", 1));
+        assert!(stats.p99_us.abs_diff(MAX_LATENCY_US) <= MAX_LATENCY_US / 100);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn record_latency_concurrently() {
+        let state = Arc::new(AppState::default());
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    for _ in 0..1000 {
+                        state.record_latency("/ping", Duration::from_micros(250));
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let latencies = state.get_latency_percentiles();
+        assert_eq!(latencies[0].count, 8000);
+        assert_eq!(latencies[0].p99_us, 250);
+    }
+
+    #[tokio::test]
+    async fn latencies_are_kept_per_route() {
+        let state = Arc::new(AppState::default());
+        let app = test_router(&[], state.clone());
+        for uri in [
+            "/ping",
+            "/nope",
+            "/nope/2",
+            "/stats/ip/10.0.0.1",
+            "/stats/ip/10.0.0.2",
+        ] {
+            let request = Request::get(uri).body(Body::empty()).unwrap();
+            app.clone().oneshot(request).await.unwrap();
+        }
+
+        let mut routes: Vec<_> = state
+            .get_latency_percentiles()
+            .into_iter()
+            .map(|latency| (latency.path, latency.count))
+            .collect();
+        routes.sort();
+        assert_eq!(
+            routes,
+            vec![
+                (UNMATCHED_PATH.to_owned(), 2),
+                ("/ping".to_owned(), 1),
+                ("/stats/ip/{addr}".to_owned(), 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn recent_counts() {
+        let state = AppState::default();
+        let ip1 = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let ip2 = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+        let start = Instant::now();
+
+        state.record_recent(ip1, start);
+        state.record_recent(ip1, start + Duration::from_millis(500));
+        state.record_recent(ip2, start + Duration::from_secs(30));
+        state.record_recent(ip1, start + Duration::from_secs(50));
+
+        let now = start + Duration::from_secs(59);
+        assert_eq!(
+            state.recent_counts_at(Duration::from_secs(60), now),
+            vec![(ip1, 3), (ip2, 1)]
+        );
+        assert_eq!(
+            state.recent_counts_at(Duration::from_secs(20), now),
+            vec![(ip1, 1)]
+        );
+    }
+
+    #[test]
+    fn active_ip_counts() {
+        let state = AppState::default();
+        let ip1 = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let ip2 = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+        let start = Instant::now();
+
+        state.ip_counts.add_at(&ip1, 5, start);
+        state.ip_counts.add_at(&ip2, 1, start);
+        state
+            .ip_counts
+            .add_at(&ip2, 1, start + Duration::from_secs(40));
+
+        // ip1 has been idle for longer than the window, despite its higher count
+        let now = start + Duration::from_secs(45);
+        assert_eq!(
+            state.active_ip_counts_at(Duration::from_secs(30), now),
+            vec![(ip2, 2)]
+        );
+        assert_eq!(
+            state.active_ip_counts_at(Duration::from_secs(60), now),
+            vec![(ip1, 5), (ip2, 2)]
+        );
+    }
+
+    #[tokio::test]
+    async fn stats_active_handler() {
+        let state = Arc::new(AppState::default());
+        let request = Request::get("/stats/active?window=30")
+            .body(Body::empty())
+            .unwrap();
+        let response = test_router(&[], state).oneshot(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json, serde_json::json!([{"ip": "127.0.0.1", "count": 1}]));
+    }
+
+    #[test]
+    fn prune_recent() {
+        let state = AppState::default();
+        let ip1 = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let ip2 = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+        let start = Instant::now();
+
+        state.record_recent(ip1, start);
+        state.record_recent(ip2, start + MAX_RECENT_WINDOW);
+        state.prune_recent(start + MAX_RECENT_WINDOW + Duration::from_secs(1));
+
+        assert!(!state.recent.contains_key(&ip1));
+        assert_eq!(state.recent.get(&ip2).unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn prune_stats_without_printer() {
+        let state = Arc::new(AppState::default());
+        let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let long_ago = Instant::now().checked_sub(MAX_RECENT_WINDOW * 2).unwrap();
+        state.record_recent(ip, long_ago);
+        state.record_rate(ip, long_ago);
+
+        let shutdown = CancellationToken::new();
+        let pruner = tokio::spawn(prune_stats(
+            state.clone(),
+            Duration::from_millis(10),
+            shutdown.clone(),
+        ));
+        time::timeout(Duration::from_secs(5), async {
+            while !state.recent.is_empty() || !state.rates.is_empty() {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        shutdown.cancel();
+        pruner.await.unwrap();
+    }
+
+    #[test]
+    fn decaying_rates() {
+        let state = AppState::default();
+        let steady = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let stopped = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let start = Instant::now();
+
+        // 5 requests per second for a minute
+        for i in 0..300 {
+            state.record_rate(steady, start + Duration::from_millis(200 * i));
+        }
+        state.record_rate(stopped, start);
+
+        let now = start + Duration::from_secs(60);
+        let rates = state.rates_at(now);
+        assert_eq!(rates[0].ip, steady);
+        assert!((4.5..5.5).contains(&rates[0].requests_per_second));
+        assert_eq!(rates[1].ip, stopped);
+        assert!(rates[1].requests_per_second < 0.01);
+
+        state.prune_rates(now + Duration::from_secs(60));
+        assert!(state.rates.contains_key(&steady));
+        assert!(!state.rates.contains_key(&stopped));
+    }
+
+    #[test]
+    fn network_prefix() {
+        let v4 = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 77));
+        assert_eq!(
+            super::network_prefix(v4, 24, 64),
+            IpAddr::V4(Ipv4Addr::new(192, 168, 1, 0))
+        );
+        assert_eq!(super::network_prefix(v4, 32, 64), v4);
+        assert_eq!(
+            super::network_prefix(v4, 0, 64),
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        );
+
+        let v6: IpAddr = "2001:db8:1:2:3:4:5:6".parse().unwrap();
+        assert_eq!(
+            super::network_prefix(v6, 24, 64),
+            "2001:db8:1:2::".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn anonymize_ip() {
+        assert_eq!(
+            super::anonymize_ip(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))),
+            IpAddr::V4(Ipv4Addr::new(1, 2, 3, 0))
+        );
+        assert_eq!(
+            super::anonymize_ip("2001:db8:1:2:3:4:5:6".parse().unwrap()),
+            "2001:db8:1::".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn anonymize_before_counting() {
+        let state = Arc::new(AppState {
+            anonymize: true,
+            ..Default::default()
+        });
+        let app = build_router(&Args::try_parse_from(["tomoru"]).unwrap(), state.clone());
+
+        for peer in ["1.2.3.4:4000", "1.2.3.200:4000", "[2001:db8:1:2::7]:4000"] {
+            let peer: SocketAddr = peer.parse().unwrap();
+            let mut request = Request::get("/ping").body(Body::empty()).unwrap();
+            request.extensions_mut().insert(ConnectInfo(peer));
+            app.clone().oneshot(request).await.unwrap();
+        }
+
+        // The raw IPs were never stored anywhere
+        assert_eq!(
+            state.get_sorted_ip_counts(),
+            vec![
+                (IpAddr::V4(Ipv4Addr::new(1, 2, 3, 0)), 2),
+                ("2001:db8:1::".parse().unwrap(), 1)
+            ]
+        );
+        assert!(state
+            .recent
+            .contains_key(&IpAddr::V4(Ipv4Addr::new(1, 2, 3, 0))));
+        assert!(!state
+            .recent
+            .contains_key(&IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))));
+        assert_eq!(state.paths_per_ip.len(), 2);
+    }
+
+    #[test]
+    fn get_counts_by_prefix() {
+        let state = AppState::default();
+        let ip1 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let ip2 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let ip3 = IpAddr::V4(Ipv4Addr::new(10, 0, 1, 1));
+
+        state.increment_ip_count(ip1);
+        state.increment_ip_count(ip1);
+        state.increment_ip_count(ip2);
+        state.increment_ip_count(ip3);
+
+        assert_eq!(
+            state.get_counts_by_prefix(24, 64),
+            vec![
+                (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 24, 3),
+                (IpAddr::V4(Ipv4Addr::new(10, 0, 1, 0)), 24, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn sharded_counter_spreads_keys() {
+        let counter = ShardedCounter::default();
+        for i in 0..=255 {
+            counter.increment(&IpAddr::V4(Ipv4Addr::new(10, 0, 0, i)));
+        }
+
+        let used_shards = counter
+            .shards
+            .iter()
+            .filter(|shard| !shard.read().unwrap().is_empty())
+            .count();
+        assert!(used_shards > 1);
+        assert_eq!(counter.len(), 256);
+
+        // Every key stays in the shard it was hashed to
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let index = counter.shard_index(&ip);
+        assert!(counter.shards[index].read().unwrap().contains_key(&ip));
+    }
+
+    #[test]
+    fn max_ips_evicts_lowest_count() {
+        let state = AppState {
+            ip_counts: ShardedCounter::with_max_keys(NonZeroUsize::new(2)),
+            ..Default::default()
+        };
+        let ip1 = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let ip2 = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+        let ip3 = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 3));
+
+        state.increment_ip_count(ip1);
+        state.increment_ip_count(ip1);
+        state.increment_ip_count(ip1);
+        state.increment_ip_count(ip2);
+        assert_eq!(state.get_sorted_ip_counts(), vec![(ip1, 3), (ip2, 1)]);
+
+        // Known IPs never trigger an eviction
+        state.increment_ip_count(ip2);
+        assert_eq!(state.ip_counts.len(), 2);
+
+        state.increment_ip_count(ip3);
+        assert_eq!(state.get_sorted_ip_counts(), vec![(ip1, 3), (ip3, 1)]);
+    }
+
+    #[tokio::test]
+    async fn max_ips_evicts_paths() {
+        let state = Arc::new(AppState {
+            ip_counts: ShardedCounter::with_max_keys(NonZeroUsize::new(2)),
+            ..Default::default()
+        });
+        let app = test_router(&[], state.clone());
+
+        for last in 1..=100u8 {
+            let mut request = Request::get(format!("/path/{}", last))
+                .body(Body::empty())
+                .unwrap();
+            request
+                .extensions_mut()
+                .insert(ConnectInfo(SocketAddr::from(([10, 0, 0, last], 4000))));
+            app.clone().oneshot(request).await.unwrap();
+        }
+
+        assert_eq!(state.ip_counts.len(), 2);
+        assert_eq!(state.paths_per_ip.len(), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn max_ips_holds_under_concurrency() {
+        let state = Arc::new(AppState {
+            ip_counts: ShardedCounter::with_max_keys(NonZeroUsize::new(10)),
+            ..Default::default()
+        });
+
+        let tasks: Vec<_> = (0..8u8)
+            .map(|task| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    for i in 0..100 {
+                        state.increment_ip_count(IpAddr::V4(Ipv4Addr::new(10, 0, task, i)));
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(state.ip_counts.len(), 10);
+    }
+
+    #[test]
+    fn max_ips_evicts_in_batches() {
+        let counter = ShardedCounter::with_max_keys(NonZeroUsize::new(64));
+        for last in 0..64u8 {
+            // Counts 1 to 64, so the lowest ones are known
+            counter.add(
+                &IpAddr::V4(Ipv4Addr::new(10, 0, 0, last)),
+                u64::from(last) + 1,
+            );
+        }
+
+        // A new key evicts a sixteenth of the cap, the lowest counts first
+        let new = IpAddr::V4(Ipv4Addr::new(10, 0, 1, 0));
+        counter.increment(&new);
+        assert_eq!(counter.len(), 64 - 4 + 1);
+        for last in 0..4u8 {
+            assert_eq!(
+                counter.get(&IpAddr::V4(Ipv4Addr::new(10, 0, 0, last))),
+                None
+            );
+        }
+        assert_eq!(
+            counter.get(&IpAddr::V4(Ipv4Addr::new(10, 0, 0, 4))),
+            Some(5)
+        );
+
+        // Until the counter is full again, new keys don't evict anything
+        for last in 1..4u8 {
+            counter.increment(&IpAddr::V4(Ipv4Addr::new(10, 0, 1, last)));
+        }
+        assert_eq!(counter.len(), 64);
+        assert_eq!(counter.get(&new), Some(1));
+    }
+
+    #[test]
+    fn increment_ip_count_after_panic() {
+        let state = Arc::new(AppState::default());
+        let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        state.increment_ip_count(ip);
+
+        let panicking = state.clone();
+        let result = std::thread::spawn(move || {
+            let index = panicking.ip_counts.shard_index(&ip);
+            let _guard = panicking.ip_counts.write_shard(index);
+            panic!("panic while holding the shard lock");
+        })
+        .join();
+        assert!(result.is_err());
+
+        assert_eq!(state.increment_ip_count(ip), 2);
+        assert!(state.allow_request(ip));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn increment_ip_count_concurrently() {
+        let state = Arc::new(AppState::default());
+        let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+
+        let tasks: Vec<_> = (0..64)
+            .map(|_| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    for _ in 0..1000 {
+                        state.increment_ip_count(ip);
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(state.get_sorted_ip_counts(), vec![(ip, 64 * 1000)]);
+    }
+
+    #[test]
+    fn reset() {
+        let state = AppState::default();
+        let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+
+        state.increment_ip_count(ip);
+        state.increment_path_count("/ping");
+        state.reset();
+
+        assert!(state.ip_counts.len() == 0);
+        assert!(state.path_counts.len() == 0);
+    }
+
+    #[test]
+    fn distinct_paths() {
+        let state = AppState::default();
+        let ip1 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let ip2 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+        for path in ["/ping", "/ping", "/admin", "/.env"] {
+            state.record_ip_path(ip1, path);
+        }
+        state.record_ip_path(ip2, "/ping");
+
+        assert_eq!(state.distinct_paths(&ip1), 3);
+        assert_eq!(state.distinct_paths(&ip2), 1);
+        assert_eq!(
+            state.distinct_paths(&IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3))),
+            0
+        );
+        assert_eq!(
+            state.get_scanners(2),
+            vec![IpPaths {
+                ip: ip1,
+                distinct_paths: 3
+            }]
+        );
+
+        // Removing or resetting forgets the paths too
+        state.remove_ip(&ip1);
+        assert_eq!(state.distinct_paths(&ip1), 0);
+        state.reset();
+        assert_eq!(state.distinct_paths(&ip2), 0);
+    }
+
+    #[test]
+    fn distinct_paths_capped() {
+        let state = AppState::default();
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+        for i in 0..MAX_DISTINCT_PATHS + 10 {
+            state.record_ip_path(ip, &format!("/{}", i));
+        }
+        assert_eq!(state.distinct_paths(&ip), MAX_DISTINCT_PATHS);
+    }
+
+    #[tokio::test]
+    async fn stats_scanners_handler() {
+        let state = Arc::new(AppState::default());
+        let app = test_router(&[], state.clone());
+
+        for uri in ["/ping", "/wp-login.php", "/.env", "/ping"] {
+            let request = Request::get(uri).body(Body::empty()).unwrap();
+            app.clone().oneshot(request).await.unwrap();
+        }
+
+        // The scanners request itself is counted as a fourth path
+        let request = Request::get("/stats/scanners?min_paths=4")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([{"ip": "127.0.0.1", "distinct_paths": 4}])
+        );
+
+        let request = Request::get("/stats/scanners?min_paths=5")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"[]");
+    }
+
+    #[test]
+    fn counts_since_mark() {
+        let state = AppState::default();
+        let ip1 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let ip2 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let ip3 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3));
+
+        // Everything counts before the first mark
+        state.increment_ip_count(ip1);
+        assert_eq!(state.get_counts_since_mark(), vec![(ip1, 1)]);
+
+        state.increment_ip_count(ip2);
+        state.mark();
+        assert!(state.get_counts_since_mark().is_empty());
+
+        for ip in [ip1, ip1, ip3] {
+            state.increment_ip_count(ip);
+        }
+        assert_eq!(state.get_counts_since_mark(), vec![(ip1, 2), (ip3, 1)]);
+        // The totals are left untouched
+        assert_eq!(state.total_requests(), 5);
+
+        // Resetting drops the baseline along with the counts
+        state.reset();
+        state.increment_ip_count(ip2);
+        assert_eq!(state.get_counts_since_mark(), vec![(ip2, 1)]);
+    }
+
+    #[test]
+    fn diff_snapshots() {
+        let state = AppState::default();
+        let ip1 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let ip2 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let ip3 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3));
+        let ip4 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 4));
+
+        for ip in [ip1, ip2, ip4] {
+            state.increment_ip_count(ip);
+        }
+        assert!(state.capture_snapshot("a"));
+        for ip in [ip1, ip1, ip3] {
+            state.increment_ip_count(ip);
+        }
+        // Removed IPs are only in the earlier snapshot
+        state.remove_ip(&ip2);
+        assert!(state.capture_snapshot("b"));
+
+        let diff = |ip, from, to, change| IpDiff {
+            ip,
+            from,
+            to,
+            change,
+        };
+        assert_eq!(
+            state.diff_snapshots("a", "b"),
+            Some(vec![
+                diff(ip1, 1, 3, 2),
+                diff(ip3, 0, 1, 1),
+                diff(ip2, 1, 0, -1)
+            ])
+        );
+        assert_eq!(
+            state.diff_snapshots("b", "a").unwrap()[0],
+            diff(ip2, 0, 1, 1)
+        );
+        assert_eq!(state.diff_snapshots("a", "missing"), None);
+    }
+
+    #[test]
+    fn capture_snapshot_limit() {
+        let state = AppState::default();
+        for i in 0..MAX_NAMED_SNAPSHOTS {
+            assert!(state.capture_snapshot(&i.to_string()));
+        }
+        assert!(!state.capture_snapshot("one too many"));
+        // Existing snapshots can still be captured again
+        assert!(state.capture_snapshot("0"));
+    }
+
+    #[tokio::test]
+    async fn stats_snapshot_and_diff_handlers() {
+        let state = Arc::new(AppState::default());
+        let app = test_router(&[], state);
+        let post = |uri| Request::post(uri).body(Body::empty()).unwrap();
+        let get = |uri| Request::get(uri).body(Body::empty()).unwrap();
+
+        let response = app
+            .clone()
+            .oneshot(post("/stats/snapshot/before"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        app.clone().oneshot(get("/ping")).await.unwrap();
+        app.clone()
+            .oneshot(post("/stats/snapshot/after"))
+            .await
+            .unwrap();
+
+        let response = app
+            .clone()
+            .oneshot(get("/stats/diff?from=before&to=after"))
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        // Both the ping and the second snapshot request are counted in between
+        assert_eq!(
+            json,
+            serde_json::json!([{"ip": "127.0.0.1", "from": 1, "to": 3, "change": 2}])
+        );
+
+        let response = app
+            .oneshot(get("/stats/diff?from=before&to=missing"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn stats_mark_and_since_handlers() {
+        let state = Arc::new(AppState::default());
+        let app = test_router(&[], state.clone());
+        let get = |uri| Request::get(uri).body(Body::empty()).unwrap();
+
+        app.clone().oneshot(get("/ping")).await.unwrap();
+        let request = Request::post("/stats/mark").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        app.clone().oneshot(get("/ping")).await.unwrap();
+        let response = app.oneshot(get("/stats/since")).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        // The mark request was counted before the baseline was taken,
+        // the since request before it was answered
+        assert_eq!(json, serde_json::json!([{"ip": "127.0.0.1", "count": 2}]));
+        assert_eq!(state.total_requests(), 4);
+    }
+
+    #[test]
+    fn get_count_histogram() {
+        let state = AppState::default();
+        for (last, count) in [(1, 1), (2, 2), (3, 10), (4, 11), (5, 100), (6, 101)] {
+            for _ in 0..count {
+                state.increment_ip_count(IpAddr::V4(Ipv4Addr::new(10, 0, 0, last)));
+            }
+        }
+
+        let bucket = |min, max, ips| CountBucket { min, max, ips };
+        // Counts on a boundary belong to the bucket it ends
+        assert_eq!(
+            state.get_count_histogram(&[1, 10, 100]),
+            vec![
+                bucket(1, Some(1), 1),
+                bucket(2, Some(10), 2),
+                bucket(11, Some(100), 2),
+                bucket(101, None, 1),
+            ]
+        );
+        assert_eq!(state.get_count_histogram(&[]), vec![bucket(1, None, 6)]);
+    }
+
+    #[test]
+    fn parse_histogram_bounds() {
+        assert_eq!(super::parse_histogram_bounds("1,5, 50"), Ok(vec![1, 5, 50]));
+        for invalid in ["", "0,10", "10,5", "5,5", "1,x"] {
+            assert!(super::parse_histogram_bounds(invalid).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn stats_histogram_handler() {
+        let state = Arc::new(AppState::default());
+        let app = test_router(&[], state);
+
+        let request = Request::get("/stats/histogram")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json[0], serde_json::json!({"min": 1, "max": 1, "ips": 1}));
+        assert_eq!(
+            json[5],
+            serde_json::json!({"min": 10001, "max": null, "ips": 0})
+        );
+
+        let request = Request::get("/stats/histogram?bounds=10,5")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn get_sorted_ip_counts() {
+        let state = AppState::default();
+        let ip1 = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let ip2 = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+
+        state.increment_ip_count(ip1);
+        state.increment_ip_count(ip1);
+        state.increment_ip_count(ip2);
+
+        let sorted = state.get_sorted_ip_counts();
+        assert_eq!(sorted, vec![(ip1, 2), (ip2, 1)]);
+    }
+
+    #[test]
+    fn get_top_ip_counts() {
+        let state = AppState::default();
+        let ip1 = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let ip2 = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+
+        state.increment_ip_count(ip1);
+        state.increment_ip_count(ip1);
+        state.increment_ip_count(ip2);
+
+        let order = SortOrder::CountDesc;
+        assert_eq!(state.get_top_ip_counts(Some(1), order), vec![(ip1, 2)]);
+        assert_eq!(
+            state.get_top_ip_counts(Some(5), order),
+            vec![(ip1, 2), (ip2, 1)]
+        );
+        assert_eq!(
+            state.get_top_ip_counts(None, order),
+            vec![(ip1, 2), (ip2, 1)]
+        );
+
+        // With the least active IPs first, top picks those
+        assert_eq!(
+            state.get_top_ip_counts(Some(1), SortOrder::CountAsc),
+            vec![(ip2, 1)]
+        );
+    }
+
+    #[test]
+    fn get_ip_counts_sorted_by() {
+        let state = AppState::default();
+        let ip1 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let ip2 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let ip3 = IpAddr::V6(Ipv6Addr::LOCALHOST);
+
+        for ip in [ip3, ip3, ip3, ip1, ip2, ip2] {
+            state.increment_ip_count(ip);
+        }
+
+        assert_eq!(
+            state.get_ip_counts_sorted_by(SortOrder::CountDesc),
+            vec![(ip3, 3), (ip2, 2), (ip1, 1)]
+        );
+        assert_eq!(
+            state.get_ip_counts_sorted_by(SortOrder::CountAsc),
+            vec![(ip1, 1), (ip2, 2), (ip3, 3)]
+        );
+        assert_eq!(
+            state.get_ip_counts_sorted_by(SortOrder::Ip),
+            vec![(ip1, 1), (ip2, 2), (ip3, 3)]
+        );
+        assert_eq!(
+            state.get_sorted_ip_counts(),
+            state.get_ip_counts_sorted_by(SortOrder::default())
+        );
+    }
+
+    #[tokio::test]
+    async fn stats_sort_param() {
+        let state = Arc::new(AppState::default());
+        for ip in [
+            [10, 0, 0, 9],
+            [10, 0, 0, 9],
+            [10, 0, 0, 9],
+            [10, 0, 0, 1],
+            [10, 0, 0, 1],
+        ] {
+            state.increment_ip_count(IpAddr::from(ip));
+        }
+        let request = |uri: &str| {
+            Request::get(uri)
+                .extension(ConnectInfo(SocketAddr::from(([10, 0, 0, 5], 4000))))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let app = test_router(&[], state);
+
+        // Each request is counted from 10.0.0.5 before its handler runs
+        for (uri, expected) in [
+            (
+                "/stats.json?sort=count_desc",
+                serde_json::json!([
+                    {"ip": "10.0.0.9", "count": 3},
+                    {"ip": "10.0.0.1", "count": 2},
+                    {"ip": "10.0.0.5", "count": 1},
+                ]),
+            ),
+            (
+                "/stats.json?sort=ip",
+                serde_json::json!([
+                    {"ip": "10.0.0.1", "count": 2},
+                    {"ip": "10.0.0.5", "count": 2},
+                    {"ip": "10.0.0.9", "count": 3},
+                ]),
+            ),
+        ] {
+            let response = app.clone().oneshot(request(uri)).await.unwrap();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(json, expected);
+        }
+
+        let response = app
+            .clone()
+            .oneshot(request("/stats?sort=count_asc&top=1"))
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "IPs:\n  10.0.0.1: 2\n");
+
+        let response = app.oneshot(request("/stats?sort=random")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn snapshot_matches_live_state() {
+        let state = AppState::default();
+        let ips: Vec<IpAddr> = (1..=50)
+            .map(|i| IpAddr::V4(Ipv4Addr::new(10, 0, 0, i)))
+            .collect();
+        for (i, ip) in ips.iter().enumerate() {
+            state.ip_counts.add(ip, i as u64 + 1);
+        }
+
+        let mut snapshot = state.ip_counts.snapshot();
+        snapshot.sort();
+        let expected: Vec<_> = ips
+            .iter()
+            .zip(1..)
+            .map(|(ip, count)| (*ip, count))
+            .collect();
+        assert_eq!(snapshot, expected);
+
+        // The snapshot is owned, so no shard lock is held and later hits don't change it
+        state.increment_ip_count(ips[0]);
+        assert!(state
+            .ip_counts
+            .shards
+            .iter()
+            .all(|shard| shard.try_write().is_ok()));
+        assert_eq!(snapshot[0], (ips[0], 1));
+    }
+
+    #[test]
+    fn format_ip_stats() {
+        let state = AppState::default();
+        let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+
+        state.increment_ip_count(ip);
+
+        let formatted = state.format_ip_stats();
+        let expected = format!("IPs:\n  {}: 1\n", ip);
+        assert_eq!(formatted, expected);
+    }
+
+    #[test]
+    fn format_ip_stats_with_label() {
+        let args = Args::try_parse_from(["tomoru", "--stats-label", "frontend-ips"]).unwrap();
+        let state = AppState {
+            stats_label: Some(args.stats_label),
+            ..Default::default()
+        };
+        state.increment_ip_count(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+
+        assert_eq!(state.format_ip_stats(), "frontend-ips:\n  127.0.0.1: 1\n");
+        assert!(state.format_stats().starts_with("frontend-ips:\n"));
+    }
+
+    #[test]
+    fn format_ip_stats_with_hostnames() {
+        let state = AppState::default();
+        let named = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let failed = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let pending = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3));
+        for ip in [named, named, named, failed, failed, pending] {
+            state.increment_ip_count(ip);
+        }
+        state
+            .hostnames
+            .insert(named, Some("gateway.example.com".to_owned()));
+        state.hostnames.insert(failed, None);
+
+        assert_eq!(
+            state.format_ip_stats(),
+            "IPs:\n  10.0.0.1 (gateway.example.com): 3\n  10.0.0.2: 2\n  10.0.0.3: 1\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn resolve_top_hostnames() {
+        fn lookup(ip: IpAddr) -> Option<String> {
+            match ip {
+                IpAddr::V4(ip) if ip.octets()[3] % 2 == 1 => {
+                    Some(format!("host{}.test", ip.octets()[3]))
+                }
+                _ => None,
+            }
+        }
+
+        let state = AppState::default();
+        for last in 1..=(PTR_TOP_IPS as u8 + 2) {
+            for _ in 0..last {
+                state.increment_ip_count(IpAddr::V4(Ipv4Addr::new(10, 0, 0, last)));
+            }
+        }
+
+        super::resolve_top_hostnames(&state, lookup).await;
+
+        // Only the busiest IPs are looked up, caching failures too
+        assert_eq!(state.hostnames.len(), PTR_TOP_IPS);
+        assert!(!state
+            .hostnames
+            .contains_key(&IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))));
+        assert_eq!(
+            *state
+                .hostnames
+                .get(&IpAddr::V4(Ipv4Addr::new(10, 0, 0, 11)))
+                .unwrap(),
+            Some("host11.test".to_owned())
+        );
+        assert_eq!(
+            *state
+                .hostnames
+                .get(&IpAddr::V4(Ipv4Addr::new(10, 0, 0, 12)))
+                .unwrap(),
+            None
+        );
+
+        // Cached IPs aren't looked up again
+        super::resolve_top_hostnames(&state, |_| panic!("looked up again")).await;
+        assert_eq!(state.hostnames.len(), PTR_TOP_IPS);
+    }
+
+    #[test]
+    fn format_ip_stats_with_percent() {
+        let state = AppState {
+            stats_percent: true,
+            ..Default::default()
+        };
+        let ips = [[10, 0, 0, 1], [10, 0, 0, 2], [10, 0, 0, 3]];
+        for (ip, count) in ips.into_iter().zip([4, 3, 1]) {
+            for _ in 0..count {
+                state.increment_ip_count(IpAddr::from(ip));
+            }
+        }
+
+        assert_eq!(
+            state.format_ip_stats(),
+            "IPs:\n  10.0.0.1: 4 (50.0%)\n  10.0.0.2: 3 (37.5%)\n  10.0.0.3: 1 (12.5%)\n"
+        );
+        // Shares stay relative to all requests when only the top counts are shown
+        assert_eq!(
+            state.format_ip_counts(state.get_top_ip_counts(Some(1), SortOrder::CountDesc)),
+            "IPs:\n  10.0.0.1: 4 (50.0%)\n"
+        );
+    }
+
+    #[test]
+    fn format_ip_stats_with_top() {
+        let state = AppState {
+            stats_top: Some(2),
+            ..Default::default()
+        };
+        for (last, count) in [(1, 5), (2, 4), (3, 2), (4, 1)] {
+            for _ in 0..count {
+                state.increment_ip_count(IpAddr::V4(Ipv4Addr::new(10, 0, 0, last)));
+            }
+        }
+
+        assert_eq!(
+            state.format_ip_stats(),
+            "IPs:\n  10.0.0.1: 5\n  10.0.0.2: 4\n  other (2 ips): 3\n"
+        );
+
+        // No "other" line when every IP fits
+        let state = AppState {
+            stats_top: Some(2),
+            ..Default::default()
+        };
+        state.increment_ip_count(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(state.format_ip_stats(), "IPs:\n  10.0.0.1: 1\n");
+    }
+
+    #[test]
+    fn format_ip_stats_with_color() {
+        let counts = [(1, 20), (2, 10), (3, 2), (4, 1)];
+        let state_with = |color| {
+            let state = AppState {
+                color,
+                ..Default::default()
+            };
+            for (last, count) in counts {
+                for _ in 0..count {
+                    state.increment_ip_count(IpAddr::V4(Ipv4Addr::new(10, 0, 0, last)));
+                }
+            }
+            state
+        };
+
+        // Without color the output stays plain
+        let plain = "IPs:\n  10.0.0.1: 20\n  10.0.0.2: 10\n  10.0.0.3: 2\n  10.0.0.4: 1\n";
+        assert_eq!(state_with(false).format_ip_stats(), plain);
+
+        let state = state_with(true);
+        assert_eq!(
+            state.format_ip_stats(),
+            "IPs:\n  \x1b[31m10.0.0.1: 20\x1b[0m\n  \x1b[31m10.0.0.2: 10\x1b[0m\n  \
+             \x1b[33m10.0.0.3: 2\x1b[0m\n  10.0.0.4: 1\n"
+        );
+        // The HTTP endpoints are never colored
+        assert_eq!(state.format_ip_counts(state.get_sorted_ip_counts()), plain);
+    }
+
+    #[test]
+    fn log_stats_with_color() {
+        let logs = LogBuffer::default();
+        let subscriber = log_subscriber(LogFormat::Text, EnvFilter::new("info"), {
+            let logs = logs.clone();
+            move || logs.clone()
+        });
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let state = AppState {
+            color: true,
+            ..Default::default()
+        };
+        state.increment_ip_count(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        let mut out = Vec::new();
+        log_stats_to(&state, &mut out);
+
+        // The escape codes reach the terminal as such, not escaped by the log output
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("IPs:\n  \x1b[31m10.0.0.1: 1\x1b[0m\n"));
+        let contents = logs.contents();
+        assert!(contents.contains("Request stats (IPv4: 1  IPv6: 0):"));
+        assert!(!contents.contains("10.0.0.1"));
+
+        // Without color everything stays in the log output
+        let state = AppState::default();
+        state.increment_ip_count(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+        let mut out = Vec::new();
+        log_stats_to(&state, &mut out);
+        assert!(out.is_empty());
+        assert!(logs.contents().contains("  10.0.0.2: 1\n"));
+    }
+
+    #[test]
+    fn use_color() {
+        let color = |args: &[&str]| {
+            let args = Args::try_parse_from(std::iter::once("tomoru").chain(args.iter().copied()))
+                .unwrap();
+            super::use_color(&args)
+        };
+        assert!(!color(&["--color", "never"]));
+        assert!(color(&["--color", "always"]));
+        // Stats written elsewhere than the terminal stay plain
+        assert!(!color(&["--color", "always", "--stats-file", "stats.log"]));
+        assert!(!color(&["--color", "always", "--log-format", "json"]));
+
+        assert!(!ColorMode::Auto.enabled(false));
+        assert!(ColorMode::Auto.enabled(true));
+    }
+
+    #[test]
+    fn percent_of() {
+        assert_eq!(super::percent_of(1, 4), 25.0);
+        assert_eq!(super::percent_of(7, 7), 100.0);
+        assert_eq!(format!("{:.1}", super::percent_of(1, 3)), "33.3");
+        assert_eq!(super::percent_of(0, 0), 0.0);
+    }
+
+    #[tokio::test]
+    async fn stats_text_handler() {
+        let app_state = Arc::new(AppState::default());
+        let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+
+        app_state.increment_ip_count(ip);
+
+        let body = stats_text(
+            State(app_state),
+            ApiQuery(TopParams {
+                top: None,
+                sort: SortOrder::default(),
+            }),
+        )
+        .await;
+        assert_eq!(body, format!("IPs:\n  {}: 1\n", ip));
+    }
+
+    #[test]
+    fn ip_count_entries_json() {
+        let state = AppState::default();
+        let ip1 = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
+        let ip2 = IpAddr::V4(Ipv4Addr::new(5, 6, 7, 8));
+
+        state.increment_ip_count(ip1);
+        state.increment_ip_count(ip2);
+        state.increment_ip_count(ip2);
+
+        let json =
+            serde_json::to_value(state.get_ip_count_entries(None, SortOrder::CountDesc)).unwrap();
+        let expected = serde_json::json!([
+            {"ip": "5.6.7.8", "count": 2},
+            {"ip": "1.2.3.4", "count": 1},
+        ]);
+        assert_eq!(json, expected);
+    }
+
+    #[tokio::test]
+    async fn ignored_ips_are_not_counted() {
+        let state = Arc::new(AppState {
+            trust_proxy: true,
+            ignored_ips: HashSet::from([IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]),
+            ..Default::default()
+        });
+        let app = test_router(&[], state.clone());
+
+        let request = Request::get("/ping")
+            .header("x-forwarded-for", "10.0.0.1")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(state.total_requests(), 0);
+        assert_eq!(state.get_sorted_path_counts(), vec![]);
+
+        let request = Request::get("/ping")
+            .header("x-forwarded-for", "10.0.0.2")
+            .body(Body::empty())
+            .unwrap();
+        app.oneshot(request).await.unwrap();
+        assert_eq!(
+            state.get_sorted_ip_counts(),
+            vec![(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 1)]
+        );
+    }
+
+    #[test]
+    fn count_for() {
+        let state = AppState::default();
+        let ip = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        assert_eq!(state.count_for(&ip), None);
+
+        state.increment_ip_count(ip);
+        state.increment_ip_count(ip);
+        assert_eq!(state.count_for(&ip), Some(2));
+        assert_eq!(state.count_for(&IpAddr::V4(Ipv4Addr::LOCALHOST)), None);
+    }
+
+    #[tokio::test]
+    async fn stats_ip_handler() {
+        let state = Arc::new(AppState::default());
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        state.increment_ip_count(ip);
+        state.record_ip_path(ip, "/ping");
+        state.record_ip_path(ip, "/.env");
+        let app = test_router(&[], state);
+
+        let request = Request::get("/stats/ip/10.0.0.1")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"ip": "10.0.0.1", "count": 1, "distinct_paths": 2})
+        );
+
+        for (uri, status) in [
+            ("/stats/ip/10.0.0.2", StatusCode::NOT_FOUND),
+            ("/stats/ip/not-an-ip", StatusCode::BAD_REQUEST),
+        ] {
+            let request = Request::get(uri).body(Body::empty()).unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), status);
+        }
+    }
+
+    #[test]
+    fn remove_ip() {
+        let state = AppState::default();
+        let ip1 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let ip2 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        state.increment_ip_count(ip1);
+        state.increment_ip_count(ip1);
+        state.increment_ip_count(ip2);
+
+        assert_eq!(state.remove_ip(&ip1), Some(2));
+        assert_eq!(state.remove_ip(&ip1), None);
+        assert_eq!(state.get_sorted_ip_counts(), vec![(ip2, 1)]);
+    }
+
+    #[tokio::test]
+    async fn reset_ip_handler() {
+        let state = Arc::new(AppState::default());
+        state.increment_ip_count(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        let app = test_router(&["--enable-reset"], state.clone());
+
+        let request = Request::post("/stats/reset-ip/10.0.0.1")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json, serde_json::json!({"ip": "10.0.0.1", "count": 1}));
+        assert_eq!(
+            state.count_for(&IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))),
+            None
+        );
+
+        for (uri, status) in [
+            ("/stats/reset-ip/10.0.0.1", StatusCode::NOT_FOUND),
+            ("/stats/reset-ip/not-an-ip", StatusCode::BAD_REQUEST),
+        ] {
+            let request = Request::post(uri).body(Body::empty()).unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), status);
+        }
+
+        // Without --enable-reset the route doesn't exist
+        let app = test_router(&[], state);
+        let request = Request::post("/stats/reset-ip/127.0.0.1")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn decay_counts() {
+        let state = AppState {
+            decay: Some(0.5),
+            ..Default::default()
+        };
+        let busy = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let idle = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        state.ip_counts.add(&busy, 100);
+        state.ip_counts.add(&idle, 3);
+
+        state.decay_counts();
+        assert_eq!(state.get_sorted_ip_counts(), vec![(busy, 50), (idle, 1)]);
+
+        // Counts reaching 0 are dropped, along with their paths
+        state.record_ip_path(idle, "/ping");
+        state.decay_counts();
+        assert_eq!(state.get_sorted_ip_counts(), vec![(busy, 25)]);
+        assert_eq!(state.distinct_paths(&idle), 0);
+
+        // Decay is opt-in
+        let state = AppState::default();
+        state.ip_counts.add(&busy, 100);
+        state.decay_counts();
+        assert_eq!(state.count_for(&busy), Some(100));
+    }
+
+    #[test]
+    fn parse_decay() {
+        assert_eq!(super::parse_decay("0.9"), Ok(0.9));
+        assert_eq!(super::parse_decay("1"), Ok(1.0));
+        for invalid in ["0", "1.5", "-0.5", "NaN", "fast"] {
+            assert!(super::parse_decay(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn merge_overlapping() {
+        let ip1 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let ip2 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let ip3 = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        let state = AppState::default();
+        let other = AppState::default();
+        for ip in [ip1, ip1, ip2] {
+            state.increment_ip_count(ip);
+        }
+        for ip in [ip1, ip3, ip3, ip3, ip3] {
+            other.increment_ip_count(ip);
+        }
+
+        // Shared IPs are summed, the others are carried over as they are
+        state.merge(&other);
+        assert_eq!(
+            state.get_ip_counts_sorted_by(SortOrder::Ip),
+            vec![(ip1, 3), (ip2, 1), (ip3, 4)]
+        );
+        assert_eq!(state.total_requests(), 8);
+
+        // The other state is left untouched
+        assert_eq!(
+            other.get_ip_counts_sorted_by(SortOrder::Ip),
+            vec![(ip1, 1), (ip3, 4)]
+        );
+    }
+
+    #[test]
+    fn merge_disjoint() {
+        let ip1 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let ip2 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let state = AppState::default();
+        let other = AppState::default();
+        state.increment_ip_count(ip1);
+        other.increment_ip_count(ip2);
+        other.increment_ip_count(ip2);
+
+        state.merge(&other);
+        assert_eq!(state.get_sorted_ip_counts(), vec![(ip2, 2), (ip1, 1)]);
+
+        // Merging an empty state changes nothing
+        state.merge(&AppState::default());
+        assert_eq!(state.get_sorted_ip_counts(), vec![(ip2, 2), (ip1, 1)]);
+    }
+
+    #[test]
+    fn total_requests_and_unique_ips() {
+        let state = AppState::default();
+        assert_eq!(state.total_requests(), 0);
+        assert_eq!(state.unique_ip_count(), 0);
+
+        for i in 1..=3 {
+            let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, i));
+            for _ in 0..i {
+                state.increment_ip_count(ip);
+            }
+        }
+        assert_eq!(state.total_requests(), 6);
+        assert_eq!(state.unique_ip_count(), 3);
+    }
+
+    #[test]
+    fn top_ip() {
+        let state = AppState::default();
+        assert_eq!(state.top_ip(), None);
+
+        let ip1 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let ip2 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let ip3 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3));
+        state.ip_counts.add(&ip2, 5);
+        state.ip_counts.add(&ip3, 1);
+        assert_eq!(state.top_ip(), Some((ip2, 5)));
+
+        // Ties go to the lowest address, whatever the map order
+        state.ip_counts.add(&ip3, 4);
+        assert_eq!(state.top_ip(), Some((ip2, 5)));
+        state.ip_counts.add(&ip1, 5);
+        assert_eq!(state.top_ip(), Some((ip1, 5)));
+    }
+
+    #[test]
+    fn request_totals_by_ip_version() {
+        let state = AppState::default();
+        assert_eq!(state.ipv4_request_total(), 0);
+        assert_eq!(state.ipv6_request_total(), 0);
+
+        state
+            .ip_counts
+            .add(&IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 100);
+        state
+            .ip_counts
+            .add(&IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 20);
+        state.ip_counts.add(&IpAddr::V6(Ipv6Addr::LOCALHOST), 50);
+        state.ip_counts.add(&"2001:db8::1".parse().unwrap(), 5);
+
+        assert_eq!(state.ipv4_request_total(), 120);
+        assert_eq!(state.ipv6_request_total(), 55);
+        assert_eq!(state.total_requests(), 175);
+    }
+
+    #[tokio::test]
+    async fn stats_summary_handler() {
+        let state = Arc::new(AppState::default());
+        state.increment_ip_count(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        let app = test_router(&[], state);
+
+        let request = Request::get("/stats/summary").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        // The summary request itself is counted before the handler runs
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "total_requests": 2,
+                "unique_ips": 2,
+                "ipv4_requests": 2,
+                "ipv6_requests": 0,
+                "top_ip": {"ip": "10.0.0.1", "count": 1}
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn stats_csv_handler() {
+        let state = Arc::new(AppState::default());
+        let ip = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
+        state.increment_ip_count(ip);
+        state.increment_ip_count(ip);
+        let app = test_router(&[], state);
+
+        let request = Request::get("/stats.csv").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/csv");
+        assert_eq!(
+            response.headers()[header::CONTENT_DISPOSITION],
+            "attachment; filename=\"tomoru-stats.csv\""
+        );
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "ip,count\n1.2.3.4,2\n127.0.0.1,1\n");
+    }
+
+    #[test]
+    fn format_prometheus_metrics() {
+        let state = AppState::default();
+        let ip1 = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
+        let ip2 = IpAddr::V4(Ipv4Addr::new(5, 6, 7, 8));
+
+        state.increment_ip_count(ip1);
+        state.increment_ip_count(ip1);
+        state.increment_ip_count(ip2);
+
+        let formatted = state.format_prometheus_metrics();
+        let expected =
+            "# HELP tomoru_requests_total Total number of HTTP requests per client IP.\n\
+                        # TYPE tomoru_requests_total counter\n\
+                        tomoru_requests_total{ip=\"1.2.3.4\"} 2\n\
+                        tomoru_requests_total{ip=\"5.6.7.8\"} 1\n\
+                        # HELP tomoru_requests_served_total Total number of HTTP requests counted.\n\
+                        # TYPE tomoru_requests_served_total counter\n\
+                        tomoru_requests_served_total 3\n\
+                        # HELP tomoru_unique_ips Number of distinct client IPs counted.\n\
+                        # TYPE tomoru_unique_ips gauge\n\
+                        tomoru_unique_ips 2\n\
+                        # HELP tomoru_in_flight_requests Number of HTTP requests being handled.\n\
+                        # TYPE tomoru_in_flight_requests gauge\n\
+                        tomoru_in_flight_requests 0\n";
+        assert_eq!(formatted, expected);
+
+        // The served counter keeps counting across resets
+        state.reset();
+        state.increment_ip_count(ip2);
+        assert!(state
+            .format_prometheus_metrics()
+            .contains("\ntomoru_requests_served_total 4\n"));
+
+        let state = AppState {
+            started: Some(Instant::now()),
+            ..Default::default()
+        };
+        assert!(state
+            .format_prometheus_metrics()
+            .ends_with("# TYPE tomoru_uptime_seconds gauge\ntomoru_uptime_seconds 0\n"));
+    }
+
+    #[test]
+    fn forwarded_for_ip() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(super::forwarded_for_ip(&headers), None);
+
+        headers.insert("x-forwarded-for", "1.2.3.4, 10.0.0.1".parse().unwrap());
+        assert_eq!(
+            super::forwarded_for_ip(&headers),
+            Some(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)))
+        );
+
+        headers.insert("x-forwarded-for", "garbage".parse().unwrap());
+        assert_eq!(super::forwarded_for_ip(&headers), None);
+    }
+
+    #[test]
+    fn counts_path() {
+        let state = AppState::default();
+        assert!(state.counts_path("/assets/app.js"));
+
+        let state = AppState {
+            count_path_prefixes: vec!["/api/".to_owned(), "/ping".to_owned()],
+            ..Default::default()
+        };
+        assert!(state.counts_path("/api/users"));
+        assert!(state.counts_path("/ping"));
+        assert!(!state.counts_path("/assets/app.js"));
+        assert!(!state.counts_path("/api"));
+    }
+
+    #[tokio::test]
+    async fn count_path_prefix_skips_other_paths() {
+        let state = Arc::new(AppState {
+            count_path_prefixes: vec!["/ping".to_owned()],
+            ..Default::default()
+        });
+        let app = test_router(&[], state.clone());
+
+        for uri in ["/ping", "/stats", "/favicon.ico"] {
+            let request = Request::get(uri).body(Body::empty()).unwrap();
+            app.clone().oneshot(request).await.unwrap();
+        }
+
+        // Other paths are still served, just not counted
+        assert_eq!(
+            state.get_sorted_ip_counts(),
+            vec![(IpAddr::V4(Ipv4Addr::LOCALHOST), 1)]
+        );
+        assert_eq!(
+            state.get_sorted_path_counts(),
+            vec![("/ping".to_owned(), 1)]
+        );
+    }
+
+    #[test]
+    fn client_ip() {
+        let peer = SocketAddr::from(([10, 0, 0, 1], 5000));
+        let forwarded = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", forwarded.to_string().parse().unwrap());
+
+        let untrusted = AppState::default();
+        assert_eq!(untrusted.client_ip(&headers, peer), peer.ip());
+
+        let trusted = AppState {
+            trust_proxy: true,
+            ..Default::default()
+        };
+        assert_eq!(trusted.client_ip(&headers, peer), forwarded);
+        assert_eq!(trusted.client_ip(&HeaderMap::new(), peer), peer.ip());
+    }
+
+    #[test]
+    fn client_ip_from_real_ip_header() {
+        let peer = SocketAddr::from(([10, 0, 0, 1], 5000));
+        let state = AppState {
+            real_ip_header: Some(HeaderName::from_static("cf-connecting-ip")),
+            ..Default::default()
+        };
+
+        let mut headers = HeaderMap::new();
+        headers.insert("cf-connecting-ip", "203.0.113.7".parse().unwrap());
+        assert_eq!(
+            state.client_ip(&headers, peer),
+            IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7))
+        );
+
+        headers.insert("cf-connecting-ip", "not-an-ip".parse().unwrap());
+        assert_eq!(state.client_ip(&headers, peer), peer.ip());
+        assert_eq!(state.client_ip(&HeaderMap::new(), peer), peer.ip());
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "stats-printer")]
+    async fn print_stats_stops_on_shutdown() {
+        let shutdown = CancellationToken::new();
+        shutdown.cancel();
+
+        // Would loop forever if the cancellation was ignored
+        time::timeout(
+            Duration::from_secs(5),
+            print_stats(
+                Arc::new(AppState::default()),
+                Duration::from_secs(1),
+                None,
+                None,
+                None,
+                shutdown,
+            ),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[test]
+    fn printer_only_flags() {
+        let parse = |args: &[&str]| {
+            Args::try_parse_from(std::iter::once("tomoru").chain(args.iter().copied())).unwrap()
+        };
+        assert!(super::printer_only_flags(&parse(&[])).is_empty());
+        assert!(super::printer_only_flags(&parse(&["--color", "never"])).is_empty());
+        assert_eq!(
+            super::printer_only_flags(&parse(&[
+                "--decay",
+                "0.5",
+                "--history-minutes",
+                "10",
+                "--color",
+                "always"
+            ])),
+            ["--decay", "--history-minutes", "--color"]
+        );
+
+        assert!(!printer_enabled(&parse(&["--quiet"])));
+        assert!(!printer_enabled(&parse(&["--stats-interval", "0"])));
+        assert_eq!(
+            printer_enabled(&parse(&[])),
+            cfg!(feature = "stats-printer")
+        );
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "stats-printer")]
+    async fn quiet_skips_printer() {
+        let stats = Arc::new(AppState::default());
+        let shutdown = CancellationToken::new();
+
+        let args = Args::try_parse_from(["tomoru"]).unwrap();
+        let printer = spawn_printer(&args, &stats, None, &shutdown);
+        assert!(printer.is_some());
+
+        let args = Args::try_parse_from(["tomoru", "--quiet"]).unwrap();
+        assert!(spawn_printer(&args, &stats, None, &shutdown).is_none());
+
+        shutdown.cancel();
+        printer.unwrap().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn save_and_load_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let ip1 = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let ip2 = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+
+        let state = AppState::default();
+        state.increment_ip_count(ip1);
+        state.increment_ip_count(ip1);
+        state.increment_ip_count(ip2);
+        save_state(&state, &path).await.unwrap();
+
+        let restored = AppState::default();
+        restored.restore(load_state(&path));
+        assert_eq!(restored.get_sorted_ip_counts(), vec![(ip1, 2), (ip2, 1)]);
+    }
+
+    #[tokio::test]
+    async fn stats_export_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let saved = dir.path().join("saved.json");
+        let exported = dir.path().join("exported.json");
+        let ip1 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let ip2: IpAddr = "2001:db8::1".parse().unwrap();
+
+        let state = Arc::new(AppState::default());
+        for ip in [ip1, ip1, ip1, ip2] {
+            state.increment_ip_count(ip);
+        }
+        let app = test_router(&[], state.clone());
+
+        let request = Request::get("/stats/export").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        tokio::fs::write(&exported, &body).await.unwrap();
+
+        // Byte for byte what the state file would hold, export request included
+        save_state(&state, &saved).await.unwrap();
+        assert_eq!(tokio::fs::read(&saved).await.unwrap(), body);
+
+        let imported = AppState::default();
+        imported.restore(load_state(&exported));
+        assert_eq!(imported.to_persisted(), state.to_persisted());
+        assert_eq!(
+            imported.to_persisted(),
+            PersistedCounts::from([(ip1, 3), (ip2, 1), (IpAddr::V4(Ipv4Addr::LOCALHOST), 1)])
+        );
+    }
+
+    #[tokio::test]
+    async fn replica_serves_reloaded_counts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let state = Arc::new(AppState {
+            replica: true,
+            ..Default::default()
+        });
+
+        std::fs::write(&path, r#"{"10.0.0.1": 5}"#).unwrap();
+        reload_state(&state, &path).await.unwrap();
+        assert_eq!(state.get_sorted_ip_counts(), vec![(ip, 5)]);
+
+        // Own requests aren't counted
+        let app = test_router(&[], state.clone());
+        let response = app
+            .oneshot(Request::get("/ping").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(state.get_sorted_ip_counts(), vec![(ip, 5)]);
+
+        // Reloads replace the counts, and keep them if the file is unreadable
+        std::fs::write(&path, r#"{"10.0.0.1": 7}"#).unwrap();
+        reload_state(&state, &path).await.unwrap();
+        std::fs::write(&path, "not json").unwrap();
+        assert!(reload_state(&state, &path).await.is_err());
+        assert_eq!(state.get_sorted_ip_counts(), vec![(ip, 7)]);
+    }
+
+    #[test]
+    fn load_missing_or_corrupt_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        assert!(load_state(&path).is_empty());
+
+        std::fs::write(&path, "not json").unwrap();
+        assert!(load_state(&path).is_empty());
+    }
+
+    #[test]
+    fn env_vars() {
+        use clap::CommandFactory;
+
+        let command = Args::command();
+        let env_of = |id: &str| {
+            command
+                .get_arguments()
+                .find(|arg| arg.get_id() == id)
+                .and_then(|arg| arg.get_env())
+                .map(|env| env.to_str().unwrap().to_owned())
+        };
+        assert_eq!(env_of("bind").as_deref(), Some("TOMORU_BIND"));
+        assert_eq!(
+            env_of("stats_interval").as_deref(),
+            Some("TOMORU_STATS_INTERVAL")
+        );
+        assert_eq!(env_of("state_file").as_deref(), Some("TOMORU_STATE_FILE"));
+    }
+
+    #[test]
+    fn env_var_precedence() {
+        // Only TOMORU_STATE_FILE is touched here since no other test depends on it
+        std::env::set_var("TOMORU_STATE_FILE", "env.json");
+        let from_env = Args::try_parse_from(["tomoru"]).unwrap();
+        let from_flag = Args::try_parse_from(["tomoru", "--state-file", "flag.json"]).unwrap();
+        std::env::remove_var("TOMORU_STATE_FILE");
+
+        assert_eq!(from_env.state_file, Some(PathBuf::from("env.json")));
+        assert_eq!(from_flag.state_file, Some(PathBuf::from("flag.json")));
+    }
+
+    #[test]
+    fn parse_stats_interval() {
+        let args = Args::try_parse_from(["tomoru"]).unwrap();
+        assert_eq!(args.stats_interval, 1);
+
+        let args = Args::try_parse_from(["tomoru", "--stats-interval", "0"]).unwrap();
+        assert_eq!(args.stats_interval, 0);
+
+        assert!(Args::try_parse_from(["tomoru", "--stats-interval", "-1"]).is_err());
+        assert!(Args::try_parse_from(["tomoru", "--stats-interval", "often"]).is_err());
+    }
+
+    #[test]
+    fn rate_limiter() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(10));
+        let ip1 = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let ip2 = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+        let start = Instant::now();
+
+        assert!(limiter.check(ip1, start));
+        assert!(limiter.check(ip1, start + Duration::from_secs(1)));
+        assert!(!limiter.check(ip1, start + Duration::from_secs(2)));
+
+        // Other IPs have their own window
+        assert!(limiter.check(ip2, start + Duration::from_secs(2)));
+
+        // A new window starts once the previous one has passed
+        assert!(limiter.check(ip1, start + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn rate_limiter_drops_expired_windows() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(10));
+        let start = Instant::now();
+        for last in 1..=100 {
+            assert!(limiter.check(IpAddr::V4(Ipv4Addr::new(10, 0, 0, last)), start));
+        }
+        assert_eq!(limiter.windows.len(), 100);
+
+        // Only the IP still making requests keeps a window
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 1, 1));
+        assert!(limiter.check(ip, start + Duration::from_secs(10)));
+        assert_eq!(limiter.windows.len(), 1);
+        assert!(limiter.windows.contains_key(&ip));
+    }
+
+    #[test]
+    fn deduplicator() {
+        let deduplicator = Deduplicator::new(
+            HeaderName::from_static("idempotency-key"),
+            Duration::from_secs(10),
+            3,
+        );
+        let ip1 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let ip2 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let key = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("idempotency-key", HeaderValue::from_static(value));
+            headers
+        };
+        let start = Instant::now();
+
+        assert!(!deduplicator.is_repeat(ip1, &key("a"), start));
+        assert!(deduplicator.is_repeat(ip1, &key("a"), start + Duration::from_secs(5)));
+        // Other IPs and other values aren't repeats, nor requests without the header
+        assert!(!deduplicator.is_repeat(ip2, &key("a"), start));
+        assert!(!deduplicator.is_repeat(ip1, &key("b"), start));
+        assert!(!deduplicator.is_repeat(ip1, &HeaderMap::new(), start));
+        assert!(!deduplicator.is_repeat(ip1, &HeaderMap::new(), start));
+
+        // Values are forgotten once the TTL has passed since they were first seen
+        assert!(!deduplicator.is_repeat(ip1, &key("a"), start + Duration::from_secs(10)));
+        assert!(deduplicator.is_repeat(ip1, &key("a"), start + Duration::from_secs(11)));
+    }
+
+    #[test]
+    fn deduplicator_capacity() {
+        let deduplicator = Deduplicator::new(
+            HeaderName::from_static("idempotency-key"),
+            Duration::from_secs(10),
+            2,
+        );
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let now = Instant::now();
+        let key = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("idempotency-key", HeaderValue::from_static(value));
+            headers
+        };
+
+        for value in ["a", "b", "c"] {
+            assert!(!deduplicator.is_repeat(ip, &key(value), now));
+        }
+        // The oldest key made room for the newest
+        assert_eq!(deduplicator.seen.lock().unwrap().first_seen.len(), 2);
+        assert!(deduplicator.is_repeat(ip, &key("c"), now));
+        assert!(!deduplicator.is_repeat(ip, &key("a"), now));
+    }
+
+    #[tokio::test]
+    async fn dedup_header_counts_retries_once() {
+        let args = Args::try_parse_from(["tomoru", "--dedup-header", "Idempotency-Key"]).unwrap();
+        let state = Arc::new(AppState {
+            deduplicator: args
+                .dedup_header
+                .map(|header| Deduplicator::new(header, Duration::from_secs(args.dedup_ttl), 100)),
+            ..Default::default()
+        });
+        let app = test_router(&[], state.clone());
+        let request = |key: Option<&str>| {
+            let mut request = Request::get("/ping");
+            if let Some(key) = key {
+                request = request.header("Idempotency-Key", key);
+            }
+            request.body(Body::empty()).unwrap()
+        };
+
+        for key in [
+            Some("order-1"),
+            Some("order-1"),
+            Some("order-2"),
+            None,
+            None,
+        ] {
+            let response = app.clone().oneshot(request(key)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        assert_eq!(
+            state.get_sorted_ip_counts(),
+            vec![(IpAddr::V4(Ipv4Addr::LOCALHOST), 4)]
+        );
+        assert_eq!(state.status_counts.get(&200), Some(4));
+    }
+
+    #[test]
+    fn allow_request_without_rate_limit() {
+        let state = AppState::default();
+        let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+
+        for _ in 0..1000 {
+            assert!(state.allow_request(ip));
+        }
+    }
+
+    #[test]
+    fn check_ban() {
+        let state = AppState {
+            ban_threshold: Some(2),
+            ..Default::default()
+        };
+        let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+
+        let count = state.increment_ip_count(ip);
+        assert!(!state.check_ban(ip, count));
+        let count = state.increment_ip_count(ip);
+        assert!(!state.check_ban(ip, count));
+        assert!(state.banned.is_empty());
+
+        let count = state.increment_ip_count(ip);
+        assert!(state.check_ban(ip, count));
+        assert!(state.banned.contains(&ip));
+        assert_eq!(
+            state.format_ip_stats(),
+            format!("IPs:\n  {}: 3 (banned)\n", ip)
+        );
+
+        // The ban outlasts a lower count, until the IP is reset
+        state.ip_counts.scale(0.5);
+        assert!(state.check_ban(ip, state.count_for(&ip).unwrap()));
+        state.remove_ip(&ip);
+        assert!(state.banned.is_empty());
+        let count = state.increment_ip_count(ip);
+        assert!(!state.check_ban(ip, count));
+    }
+
+    #[test]
+    fn parse_blocklist() {
+        let blocklist = Blocklist::parse(
+            "# Known scanners\n10.0.0.1\n\n  2001:db8::1  \n192.168.1.77/24\n2001:db8:1::/48\n",
+        )
+        .unwrap();
+        assert_eq!(
+            blocklist,
+            Blocklist {
+                ips: HashSet::from([
+                    IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+                    IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+                ]),
+                networks: vec![
+                    (IpAddr::V4(Ipv4Addr::new(192, 168, 1, 0)), 24),
+                    (
+                        IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 1, 0, 0, 0, 0, 0)),
+                        48
+                    ),
+                ],
+            }
+        );
+        assert_eq!(blocklist.len(), 4);
+        assert_eq!(Blocklist::parse("").unwrap(), Blocklist::default());
+
+        for contents in [
+            "10.0.0.1\nnot-an-ip",
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/",
+            "/8",
+        ] {
+            assert!(Blocklist::parse(contents).is_err(), "{}", contents);
+        }
+        let error = Blocklist::parse("10.0.0.1\nnope").unwrap_err();
+        assert!(format!("{:#}", error).contains("line 2: nope"));
+    }
+
+    #[test]
+    fn blocklist_contains() {
+        let blocklist = Blocklist::parse("10.0.0.1\n192.168.0.0/16\n::/0").unwrap();
+
+        assert!(blocklist.contains(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))));
+        assert!(!blocklist.contains(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))));
+        assert!(blocklist.contains(IpAddr::V4(Ipv4Addr::new(192, 168, 255, 1))));
+        assert!(!blocklist.contains(IpAddr::V4(Ipv4Addr::new(192, 169, 0, 1))));
+        // A network only matches addresses of its own family
+        assert!(blocklist.contains(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+        assert!(!Blocklist::parse("0.0.0.0/0")
+            .unwrap()
+            .contains(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+    }
+
+    #[tokio::test]
+    async fn blocked_ips_get_403() {
+        let state = Arc::new(AppState {
+            blocklist: RwLock::new(Blocklist::parse("10.0.0.0/8").unwrap()),
+            ..Default::default()
+        });
+        let app = test_router(&[], state.clone());
+
+        let response = app.clone().oneshot(ping_from([10, 0, 0, 1])).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = app.oneshot(ping_from([11, 0, 0, 1])).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Blocked requests are still counted
+        assert_eq!(
+            state.get_ip_counts_sorted_by(SortOrder::Ip),
+            vec![
+                (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 1),
+                (IpAddr::V4(Ipv4Addr::new(11, 0, 0, 1)), 1),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn blocked_ips_get_403_on_uncounted_paths() {
+        let state = Arc::new(AppState {
+            count_path_prefixes: vec!["/api".to_string()],
+            blocklist: RwLock::new(Blocklist::parse("10.0.0.0/8").unwrap()),
+            ..Default::default()
+        });
+        state.banned.insert(IpAddr::V4(Ipv4Addr::new(11, 0, 0, 1)));
+        let app = test_router(&["--count-path-prefix", "/api"], state.clone());
+
+        for peer in [[10, 0, 0, 1], [11, 0, 0, 1]] {
+            let response = app.clone().oneshot(ping_from(peer)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        }
+        let response = app.oneshot(ping_from([12, 0, 0, 1])).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(state.ip_counts.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn blocked_ignored_ips_get_403() {
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let state = Arc::new(AppState {
+            ignored_ips: [ip].into_iter().collect(),
+            blocklist: RwLock::new(Blocklist::parse("10.0.0.0/8").unwrap()),
+            ..Default::default()
+        });
+        let app = test_router(&["--ignore-ip", "10.0.0.1"], state.clone());
+
+        let response = app.oneshot(ping_from([10, 0, 0, 1])).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(state.ip_counts.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn reload_blocklist() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blocklist.txt");
+        let state = AppState::default();
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+        std::fs::write(&path, "10.0.0.1\n").unwrap();
+        super::reload_blocklist(&state, &path).await.unwrap();
+        assert!(state.is_blocked(ip));
+
+        // A broken file keeps the current blocklist
+        std::fs::write(&path, "10.0.0.2\nnope\n").unwrap();
+        assert!(super::reload_blocklist(&state, &path).await.is_err());
+        assert!(state.is_blocked(ip));
+
+        std::fs::write(&path, "# Nothing blocked\n").unwrap();
+        super::reload_blocklist(&state, &path).await.unwrap();
+        assert!(!state.is_blocked(ip));
+
+        assert!(
+            super::reload_blocklist(&state, &dir.path().join("missing.txt"))
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn check_ban_without_threshold() {
+        let state = AppState::default();
+        let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+
+        assert!(!state.check_ban(ip, u64::MAX));
+        assert!(state.banned.is_empty());
+    }
+
+    #[tokio::test]
+    async fn load_tls_config() {
+        assert!(super::load_tls_config(None, None).await.unwrap().is_none());
+
+        let cert = Path::new("cert.pem");
+        let key = Path::new("key.pem");
+        assert!(super::load_tls_config(Some(cert), None).await.is_err());
+        assert!(super::load_tls_config(None, Some(key)).await.is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let missing_cert = dir.path().join("cert.pem");
+        let missing_key = dir.path().join("key.pem");
+        assert!(
+            super::load_tls_config(Some(&missing_cert), Some(&missing_key))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn version_is_not_counted() {
+        let state = Arc::new(AppState::default());
+        let app = test_router(&[], state.clone());
+
+        let request = Request::get("/version").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(state.total_requests(), 0);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+    }
+
+    #[tokio::test]
+    async fn healthz_is_not_counted() {
+        let state = Arc::new(AppState::default());
+        let app = test_router(&[], state.clone());
+
+        let request = Request::get("/healthz").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(state.ip_counts.len() == 0);
+
+        let request = Request::get("/ping").body(Body::empty()).unwrap();
+        app.oneshot(request).await.unwrap();
+        assert_eq!(state.ip_counts.len(), 1);
+    }
+
+    #[test]
+    fn last_seen_updates_on_repeated_hits() {
+        let state = AppState::default();
+        let ip: IpAddr = "10.1.2.3".parse().unwrap();
+        let start = Instant::now();
+
+        state.ip_counts.add_at(&ip, 1, start);
+        state
+            .ip_counts
+            .add_at(&ip, 1, start + Duration::from_secs(5));
+
+        let details = state.ip_counts.details();
+        assert_eq!(
+            details,
+            vec![(
+                ip,
+                CounterDetails {
+                    count: 2,
+                    first_seen: start,
+                    last_seen: start + Duration::from_secs(5),
+                }
+            )]
+        );
+
+        // A hit reported out of order doesn't move last_seen back
+        state
+            .ip_counts
+            .add_at(&ip, 1, start + Duration::from_secs(1));
+        let (_, details) = state.ip_counts.details()[0];
+        assert_eq!(details.count, 3);
+        assert_eq!(details.last_seen, start + Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn stats_detailed_handler() {
+        let state = Arc::new(AppState::default());
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        state.increment_ip_count(ip);
+        state.increment_ip_count(ip);
+        let app = test_router(&[], state);
+
+        let request = Request::get("/stats/detailed").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let entries: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let entry = &entries[0];
+        assert_eq!(entry["ip"], "10.0.0.1");
+        assert_eq!(entry["count"], 2);
+        let first_seen = entry["first_seen"].as_u64().unwrap();
+        assert!(first_seen <= now && now - first_seen < 60);
+        assert!(entry["last_seen"].as_u64().unwrap() >= first_seen);
+
+        // The request itself is counted too
+        assert_eq!(entries[1]["ip"], "127.0.0.1");
+        assert_eq!(entries[1]["count"], 1);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serve_uds_counts_as_loopback() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tomoru.sock");
+        let state = Arc::new(AppState::default());
+        let args = Args::try_parse_from(["tomoru"]).unwrap();
+        let shutdown = CancellationToken::new();
+        let server = tokio::spawn({
+            let app = build_router(&args, state.clone());
+            let path = path.clone();
+            let shutdown = shutdown.clone();
+            async move { serve_uds(app, &path, shutdown).await }
+        });
+
+        // Wait for the socket to be bound
+        let mut stream = loop {
+            match tokio::net::UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        stream
+            .write_all(b"GET /ping HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("pong"));
+        assert_eq!(
+            state.get_sorted_ip_counts(),
+            vec![(IpAddr::V4(Ipv4Addr::LOCALHOST), 1)]
+        );
+
+        shutdown.cancel();
+        server.await.unwrap().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn uds_conflicts_with_tls() {
+        let error =
+            Args::try_parse_from(["tomoru", "--uds", "a.sock", "--tls-cert", "a.pem"]).unwrap_err();
+        assert_eq!(error.kind(), clap::error::ErrorKind::ArgumentConflict);
+    }
+
+    // Collects formatted log output for assertions
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl LogBuffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn access_log() {
+        let logs = LogBuffer::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            })
+            .with_ansi(false)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let state = Arc::new(AppState::default());
+        let request = Request::get("/ping").body(Body::empty()).unwrap();
+        test_router(&[], state).oneshot(request).await.unwrap();
+        assert!(!logs.contents().contains("Request served"));
+
+        let state = Arc::new(AppState {
+            access_log: true,
+            ..Default::default()
+        });
+        let request = Request::get("/nope").body(Body::empty()).unwrap();
+        test_router(&[], state).oneshot(request).await.unwrap();
+
+        let contents = logs.contents();
+        assert!(contents.contains("Request served"));
+        assert!(contents.contains("ip=127.0.0.1 method=GET path=\"/nope\" request_id="));
+        assert!(contents.contains("status=404"));
+        assert!(contents.contains("latency_ms="));
+
+        // Requests that aren't counted are still logged
+        let state = Arc::new(AppState {
+            access_log: true,
+            replica: true,
+            ..Default::default()
+        });
+        let request = Request::get("/ping").body(Body::empty()).unwrap();
+        test_router(&[], state.clone())
+            .oneshot(request)
+            .await
+            .unwrap();
+        assert!(logs.contents().contains("path=\"/ping\""));
+        assert_eq!(state.ip_counts.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn request_id() {
+        let logs = LogBuffer::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            })
+            .with_ansi(false)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let state = Arc::new(AppState {
+            access_log: true,
+            ..Default::default()
+        });
+        let app = test_router(&[], state);
+
+        let request = Request::get("/ping")
+            .header("X-Request-Id", "abc-123")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.headers()["x-request-id"], "abc-123");
+        assert!(logs.contents().contains("request_id=\"abc-123\""));
+
+        // Without one, or with an unusable one, a UUID v4 is generated
+        for request in [
+            Request::get("/healthz").body(Body::empty()).unwrap(),
+            Request::get("/ping")
+                .header("X-Request-Id", "a".repeat(MAX_REQUEST_ID_LEN + 1))
+                .body(Body::empty())
+                .unwrap(),
+        ] {
+            let response = app.clone().oneshot(request).await.unwrap();
+            let id = response.headers()["x-request-id"].to_str().unwrap();
+            assert_eq!(id.len(), 36);
+            assert_eq!(id.as_bytes()[14], b'4');
+            assert!(matches!(id.as_bytes()[19], b'8' | b'9' | b'a' | b'b'));
+        }
+    }
+
+    #[test]
+    fn alert_threshold() {
+        let logs = LogBuffer::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            })
+            .with_ansi(false)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let state = AppState {
+            alert_threshold: Some(2),
+            ..Default::default()
+        };
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let alerts = || {
+            logs.contents()
+                .matches("IP went over the alert threshold")
+                .count()
+        };
+
+        state.increment_ip_count(ip);
+        state.increment_ip_count(ip);
+        assert_eq!(alerts(), 0);
+
+        state.increment_ip_count(ip);
+        assert_eq!(alerts(), 1);
+        assert!(logs.contents().contains("ip=10.0.0.1 count=3 threshold=2"));
+
+        // Later requests don't alert again
+        state.increment_ip_count(ip);
+        state.increment_ip_count(ip);
+        assert_eq!(alerts(), 1);
+
+        // Once the IP is cleared, it can alert again
+        state.remove_ip(&ip);
+        for _ in 0..3 {
+            state.increment_ip_count(ip);
+        }
+        assert_eq!(alerts(), 2);
+    }
+
+    #[tokio::test]
+    async fn ping_response() {
+        let state = Arc::new(AppState::default());
+        let app = test_router(&["--ping-response", "OK"], state);
+
+        let request = Request::get("/ping").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "OK");
+    }
+
+    #[tokio::test]
+    async fn ping_draining() {
+        let state = Arc::new(AppState::default());
+        let app = test_router(&[], state.clone());
+
+        let request = Request::get("/ping").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Load balancers see 503 once shutdown is requested
+        state.drain();
+        assert!(state.shutdown.is_cancelled());
+        let request = Request::get("/ping").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // Still counted like any other request
+        assert_eq!(state.total_requests(), 2);
+    }
+
+    #[test]
+    fn parse_port_range() {
+        assert_eq!(super::parse_port_range("3000-3010"), Ok(3000..=3010));
+        assert_eq!(super::parse_port_range("3000-3000"), Ok(3000..=3000));
+        assert!(super::parse_port_range("3000").is_err());
+        assert!(super::parse_port_range("3010-3000").is_err());
+        assert!(super::parse_port_range("3000-70000").is_err());
+    }
+
+    #[tokio::test]
+    async fn bind_tcp_port_range() {
+        let taken = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let bind_addr = SocketAddr::from(([127, 0, 0, 1], 3000));
+
+        let error = bind_tcp(bind_addr, Some(port..=port)).await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("No free port in range {}-{} on 127.0.0.1", port, port)
+        );
+
+        // The busy port is skipped in favor of a later one
+        let Some(end) = port.checked_add(20) else {
+            return;
+        };
+        let listener = bind_tcp(bind_addr, Some(port..=end)).await.unwrap();
+        let chosen = listener.local_addr().unwrap().port();
+        assert!(chosen > port && chosen <= end);
+    }
+
+    #[tokio::test]
+    async fn stats_ws_pushes_stats() {
+        use futures_util::StreamExt;
+
+        let state = Arc::new(AppState::default());
+        state.increment_ip_count(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        let args = Args::try_parse_from(["tomoru"]).unwrap();
+        let app = build_router(&args, state.clone());
+
+        let (addr, server) = spawn_server(app, &state.shutdown).await;
+
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/stats/ws", addr))
+            .await
+            .unwrap();
+        let message = time::timeout(Duration::from_secs(5), socket.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        // The upgrade request itself is counted too
+        let entries: serde_json::Value = serde_json::from_str(message.to_text().unwrap()).unwrap();
+        let mut ips: Vec<_> = entries
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| entry["ip"].as_str().unwrap())
+            .collect();
+        ips.sort();
+        assert_eq!(ips, ["10.0.0.1", "127.0.0.1"]);
+
+        // Shutdown closes the stream
+        state.shutdown.cancel();
+        let closed = time::timeout(Duration::from_secs(5), async {
+            while let Some(Ok(message)) = socket.next().await {
+                if message.is_close() {
+                    break;
+                }
+            }
+        });
+        closed.await.unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn stats_sse_emits_events() {
+        use futures_util::StreamExt;
+
+        let state = Arc::new(AppState::default());
+        let app = test_router(&[], state.clone());
+
+        let request = Request::get("/stats/sse").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/event-stream"
+        );
+
+        let mut body = response.into_body().into_data_stream();
+        let chunk = time::timeout(Duration::from_secs(5), body.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let event = std::str::from_utf8(&chunk).unwrap();
+        let data = event
+            .strip_prefix("data: ")
+            .and_then(|event| event.strip_suffix("\n\n"))
+            .unwrap();
+        let entries: serde_json::Value = serde_json::from_str(data).unwrap();
+        assert_eq!(
+            entries,
+            serde_json::json!([{"ip": "127.0.0.1", "count": 1}])
+        );
+
+        // Shutdown ends the stream
+        state.shutdown.cancel();
+        let rest = time::timeout(Duration::from_secs(5), body.collect::<Vec<_>>());
+        assert!(rest.await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn max_connections_queues_excess_requests() {
+        let args = Args::try_parse_from(["tomoru", "--max-connections", "1"]).unwrap();
+        let (entered_tx, mut entered_rx) = tokio::sync::mpsc::unbounded_channel();
+        let release = Arc::new(tokio::sync::Notify::new());
+        let app: Router = Router::new()
+            .route(
+                "/slow",
+                get({
+                    let release = release.clone();
+                    move || async move {
+                        entered_tx.send(()).unwrap();
+                        release.notified().await;
+                    }
+                }),
+            )
+            .layer(concurrency_limit(&args).unwrap());
+
+        let first = tokio::spawn(
+            app.clone()
+                .oneshot(Request::get("/slow").body(Body::empty()).unwrap()),
+        );
+        entered_rx.recv().await.unwrap();
+
+        // The second request waits while the first one holds the only slot
+        let second = tokio::spawn(app.oneshot(Request::get("/slow").body(Body::empty()).unwrap()));
+        assert!(time::timeout(Duration::from_millis(100), entered_rx.recv())
+            .await
+            .is_err());
+
+        release.notify_one();
+        first.await.unwrap().unwrap();
+        entered_rx.recv().await.unwrap();
+        release.notify_one();
+        second.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_after_duration() {
+        let state = Arc::new(AppState::default());
+        let timer = tokio::spawn(shutdown_after(Duration::from_secs(30), state.clone()));
+
+        time::sleep(Duration::from_secs(29)).await;
+        assert!(!state.shutdown.is_cancelled());
+        time::sleep(Duration::from_secs(2)).await;
+        assert!(state.shutdown.is_cancelled());
+        timer.await.unwrap();
+
+        // Like any other shutdown, it tells load balancers to move on
+        let request = Request::get("/ping").body(Body::empty()).unwrap();
+        let response = test_router(&[], state).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // An earlier shutdown stops the timer right away
+        let state = Arc::new(AppState::default());
+        let timer = tokio::spawn(shutdown_after(Duration::from_secs(30), state.clone()));
+        state.shutdown.cancel();
+        time::timeout(Duration::from_secs(1), timer)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn parse_run_for() {
+        let args = Args::try_parse_from(["tomoru", "--run-for", "60"]).unwrap();
+        assert_eq!(args.run_for, Some(60));
+        assert!(Args::try_parse_from(["tomoru", "--run-for", "0"]).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn request_timeout_is_counted() {
+        let args = Args::try_parse_from(["tomoru", "--request-timeout", "5"]).unwrap();
+        let state = Arc::new(AppState::default());
+        let routes = Router::new().route(
+            "/slow",
+            get(|| async { time::sleep(Duration::from_secs(60)).await }),
+        );
+        let app = wrap_routes(routes, &args, state.clone())
+            .layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000))));
+
+        let request = Request::get("/slow").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], br#"{"error":"Request timeout","code":408}"#);
+        assert_eq!(state.total_requests(), 1);
+        assert_eq!(state.status_counts.snapshot(), vec![(408, 1)]);
+    }
+
+    #[tokio::test]
+    async fn max_body_bytes() {
+        let args = Args::try_parse_from(["tomoru", "--max-body-bytes", "16"]).unwrap();
+        let state = Arc::new(AppState::default());
+        let routes = Router::new()
+            .route("/ignore", post(|| async { "ok" }))
+            .route("/read", post(|body: String| async move { body }));
+        let app = wrap_routes(routes, &args, state.clone())
+            .layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000))));
+        let request = |uri, body: &str, content_length| {
+            let mut request = Request::post(uri);
+            if content_length {
+                request = request.header(header::CONTENT_LENGTH, body.len());
+            }
+            request.body(Body::from(body.to_owned())).unwrap()
+        };
+
+        let under = "a".repeat(16);
+        let over = "a".repeat(17);
+        for (uri, body, content_length, status) in [
+            ("/ignore", &under, true, StatusCode::OK),
+            ("/ignore", &over, true, StatusCode::PAYLOAD_TOO_LARGE),
+            ("/read", &under, false, StatusCode::OK),
+            // Without a Content-Length, the body is cut off once read past the limit
+            ("/read", &over, false, StatusCode::PAYLOAD_TOO_LARGE),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(request(uri, body, content_length))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status, "{} {}", uri, body.len());
+        }
+        // Rejected requests are still counted
+        assert_eq!(state.total_requests(), 4);
+    }
+
+    #[tokio::test]
+    async fn errors_are_json() {
+        let app = test_router(&["--max-body-bytes", "4"], Arc::new(AppState::default()));
+        let get = |uri| Request::get(uri).body(Body::empty()).unwrap();
+
+        for (request, status, error) in [
+            (get("/stats?top=abc"), 400, "Invalid query parameters"),
+            (get("/stats/diff?from=a"), 400, "Invalid query parameters"),
+            (
+                get("/stats/subnets?v4=999"),
+                400,
+                "Invalid query parameters",
+            ),
+            (get("/stats/ip/%FF"), 400, "Invalid path"),
+            (
+                Request::delete("/stats").body(Body::empty()).unwrap(),
+                405,
+                "Method not allowed",
+            ),
+            (
+                Request::post("/stats/mark")
+                    .header(header::CONTENT_LENGTH, 5)
+                    .body(Body::from("hello"))
+                    .unwrap(),
+                413,
+                "Payload too large",
+            ),
+        ] {
+            let uri = request.uri().clone();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status().as_u16(), status, "{}", uri);
+            assert_eq!(
+                response.headers()[header::CONTENT_TYPE],
+                "application/json",
+                "{}",
+                uri
+            );
+            if status == 405 {
+                assert_eq!(response.headers()[header::ALLOW], "GET,HEAD");
+            }
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(json, serde_json::json!({"error": error, "code": status}));
+        }
+    }
+
+    // Minimal IPv4 MaxMind DB mapping 128.0.0.0/1 to country US and nothing else
+    fn test_geoip_db() -> maxminddb::Reader<Vec<u8>> {
+        // A single search tree node with 24-bit records: addresses starting with
+        // a 0 bit have no data (record == node count), the rest point to offset 0
+        // of the data section (record == node count + 16)
+        let mut db = vec![0, 0, 1, 0, 0, 17];
+        db.extend([0; 16]);
+
+        // {"country": {"iso_code": "US"}}
+        db.extend(b"\xe1\x47country\xe1\x48iso_code\x42US");
+
+        db.extend(b"\xab\xcd\xefMaxMind.com");
+        db.extend(b"\xe9");
+        db.extend(b"\x5bbinary_format_major_version\xa1\x02");
+        db.extend(b"\x5bbinary_format_minor_version\xa0");
+        db.extend(b"\x4bbuild_epoch\x00\x02");
+        db.extend(b"\x4ddatabase_type\x44Test");
+        db.extend(b"\x4bdescription\xe0");
+        db.extend(b"\x4aip_version\xa1\x04");
+        db.extend(b"\x49languages\x00\x04");
+        db.extend(b"\x4anode_count\xc1\x01");
+        db.extend(b"\x4brecord_size\xa1\x18");
+
+        maxminddb::Reader::from_source(db).unwrap()
+    }
+
+    #[tokio::test]
+    async fn country_counts() {
+        let state = Arc::new(AppState {
+            trust_proxy: true,
+            geoip: Some(test_geoip_db()),
+            ..Default::default()
+        });
+        let app = test_router(&[], state.clone());
+
+        for ip in ["203.0.113.1", "198.51.100.1", "192.0.2.1", "10.0.0.1"] {
+            let request = Request::get("/ping")
+                .header("x-forwarded-for", ip)
+                .body(Body::empty())
+                .unwrap();
+            app.clone().oneshot(request).await.unwrap();
+        }
+
+        let request = Request::get("/stats/countries")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        // Private addresses, including the one fetching the stats, are unknown
+        assert_eq!(body, "Countries:\n  US: 3\n  unknown: 2\n");
+    }
+
+    #[tokio::test]
+    async fn countries_inactive_without_geoip_db() {
+        let state = Arc::new(AppState::default());
+        let app = test_router(&[], state.clone());
+
+        let request = Request::get("/stats/countries")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(state.country_counts.len(), 0);
+    }
+
+    #[test]
+    fn load_geoip_db() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("corrupt.mmdb");
+        std::fs::write(&path, "garbage").unwrap();
+
+        assert!(super::load_geoip_db(&path).is_err());
+        assert!(super::load_geoip_db(&dir.path().join("missing.mmdb")).is_err());
+    }
+
+    #[tokio::test]
+    async fn rejected_requests_are_counted() {
+        let state = Arc::new(AppState::default());
+        let app = test_router(
+            &[
+                "--stats-user",
+                "admin",
+                "--stats-pass",
+                "hunter2",
+                "--max-connections",
+                "1",
+            ],
+            state.clone(),
+        );
+
+        // Turned away by the auth layer inside the counter layer
+        let request = Request::get("/stats").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            state.get_sorted_ip_counts(),
+            vec![(IpAddr::V4(Ipv4Addr::LOCALHOST), 1)]
+        );
+        assert_eq!(state.status_counts.get(&401), Some(1));
+    }
+
+    #[tokio::test]
+    async fn handler_panic_returns_500() {
+        async fn panicking() -> StatusCode {
+            panic!("deliberate")
+        }
+
+        let args = Args::try_parse_from(["tomoru"]).unwrap();
+        let state = Arc::new(AppState::default());
+        let routes = Router::new()
+            .route("/panic", get(panicking))
+            .route("/ping", get(|state| ping(state, "pong".into())));
+        let app = wrap_routes(routes, &args, state.clone())
+            .layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000))));
+
+        let request = Request::get("/panic").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        // The router keeps serving afterwards
+        let request = Request::get("/ping").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        assert_eq!(state.total_requests(), 2);
+        let mut statuses = state.status_counts.snapshot();
+        statuses.sort();
+        assert_eq!(statuses, vec![(200, 1), (500, 1)]);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "stats-printer")]
+    async fn print_stats_appends_to_stats_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats.log");
+        let stats = Arc::new(AppState::default());
+        stats.increment_ip_count(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        let shutdown = CancellationToken::new();
+
+        let printer = tokio::spawn(print_stats(
+            stats,
+            Duration::from_millis(10),
+            Some(path.clone()),
+            None,
+            None,
+            shutdown.clone(),
+        ));
+        time::sleep(Duration::from_millis(50)).await;
+
+        // Rotating the file away makes the printer start a new one
+        std::fs::rename(&path, dir.path().join("stats.log.1")).unwrap();
+        time::sleep(Duration::from_millis(50)).await;
+        shutdown.cancel();
+        printer.await.unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let (timestamp, snapshot) = contents.split_once('\n').unwrap();
+        assert!(timestamp.starts_with('[') && timestamp.ends_with(" GMT]"));
+        assert!(snapshot.starts_with("IPs:\n  10.0.0.1: 1\nPaths:\nMethods:\nStatuses:\n"));
+    }
+
+    #[tokio::test]
+    async fn rotate_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshots = Snapshots {
+            dir: dir.path().join("snapshots"),
+            keep: 3,
+        };
+        let stats = AppState::default();
+        stats.increment_ip_count(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+
+        // The directory is created on the first snapshot
+        super::rotate_snapshots(&stats, &snapshots, 1_700_000_000)
+            .await
+            .unwrap();
+        let first = snapshots.dir.join("tomoru-1700000000.json");
+        let restored = AppState::default();
+        restored.restore(load_state(&first));
+        assert_eq!(restored.to_persisted(), stats.to_persisted());
+
+        std::fs::write(snapshots.dir.join("notes.txt"), "kept").unwrap();
+        for unix_seconds in 1_700_000_001..=1_700_000_004 {
+            super::rotate_snapshots(&stats, &snapshots, unix_seconds)
+                .await
+                .unwrap();
+        }
+
+        // Only the newest snapshots are left, along with unrelated files
+        let mut names: Vec<String> = std::fs::read_dir(&snapshots.dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "notes.txt",
+                "tomoru-1700000002.json",
+                "tomoru-1700000003.json",
+                "tomoru-1700000004.json"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn rotate_snapshots_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        let snapshots = Snapshots {
+            dir: file.join("snapshots"),
+            keep: 3,
+        };
+
+        // The directory can't be created inside a file
+        let stats = AppState::default();
+        assert!(super::rotate_snapshots(&stats, &snapshots, 1_700_000_000)
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn format_statsd() {
+        let state = AppState::default();
+        assert_eq!(
+            state.format_statsd(),
+            "tomoru.requests.total:0|g\ntomoru.ips.unique:0|g\ntomoru.ips.top_count:0|g\n"
+        );
+
+        for ip in [[10, 0, 0, 1], [10, 0, 0, 2], [10, 0, 0, 2]] {
+            state.increment_ip_count(IpAddr::from(ip));
+        }
+        assert_eq!(
+            state.format_statsd(),
+            "tomoru.requests.total:3|g\ntomoru.ips.unique:2|g\ntomoru.ips.top_count:2|g\n"
+        );
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "stats-printer")]
+    async fn print_stats_sends_statsd() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket = connect_statsd(receiver.local_addr().unwrap())
+            .await
+            .unwrap();
+        let stats = Arc::new(AppState::default());
+        stats.increment_ip_count(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        let shutdown = CancellationToken::new();
+
+        let printer = tokio::spawn(print_stats(
+            stats,
+            Duration::from_millis(10),
+            None,
+            Some(Arc::new(socket)),
+            None,
+            shutdown.clone(),
+        ));
+
+        let mut packet = [0; 1024];
+        let len = time::timeout(Duration::from_secs(5), receiver.recv(&mut packet))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            std::str::from_utf8(&packet[..len]).unwrap(),
+            "tomoru.requests.total:1|g\ntomoru.ips.unique:1|g\ntomoru.ips.top_count:1|g\n"
+        );
+
+        shutdown.cancel();
+        printer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn resolve_statsd_error() {
+        assert!(resolve_statsd("not an address").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn open_stats_file_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing").join("stats.log");
+        let error = open_stats_file(&path).await.unwrap_err();
+        assert!(error.to_string().starts_with("Failed to open stats file"));
+    }
+
+    #[tokio::test]
+    async fn load_config() {
+        let parse = |args: &[&str]| {
+            Args::try_parse_from(std::iter::once("tomoru").chain(args.iter().copied())).unwrap()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let state_file = dir.path().join("state.json");
+        let missing_dir_file = dir.path().join("missing").join("state.json");
+
+        let config = super::load_config(&parse(&[
+            "--bind",
+            "127.0.0.1:8080",
+            "--state-file",
+            state_file.to_str().unwrap(),
+            "--stats-file",
+            "stats.log",
+            "--statsd",
+            "127.0.0.1:8125",
+        ]))
+        .await
+        .unwrap();
+        assert_eq!(
+            config.bind_addrs,
+            vec![SocketAddr::from(([127, 0, 0, 1], 8080))]
+        );
+        // Only resolved, so a dry run doesn't open a socket
+        assert_eq!(
+            config.statsd,
+            Some(SocketAddr::from(([127, 0, 0, 1], 8125)))
+        );
+        assert!(config.tls_config.is_none());
+        assert!(config.geoip.is_none());
+        assert!(!state_file.exists());
+
+        let invalid = [
+            vec!["--bind", "localhost"],
+            vec!["--tls-cert", "cert.pem"],
+            vec!["--geoip-db", "missing.mmdb"],
+            vec!["--state-file", missing_dir_file.to_str().unwrap()],
+            vec!["--stats-file", missing_dir_file.to_str().unwrap()],
+        ];
+        for args in invalid {
+            assert!(
+                super::load_config(&parse(&args)).await.is_err(),
+                "{:?}",
+                args
+            );
+        }
+    }
+
+    #[test]
+    fn log_config() {
+        let logs = LogBuffer::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            })
+            .with_ansi(false)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let args = Args::try_parse_from([
+            "tomoru",
+            "--port-range",
+            "3000-3010",
+            "--rate-limit",
+            "100",
+            "--trust-proxy",
+        ])
+        .unwrap();
+        super::log_config(&args, &[SocketAddr::from(([127, 0, 0, 1], 3000))], false);
+
+        let contents = logs.contents();
+        assert!(contents.contains("Starting with configuration"));
+        assert!(contents.contains("listen=127.0.0.1:3000-3010 tls=false stats_interval=1"));
+        assert!(contents.contains("trust_proxy=true"));
+        assert!(contents.contains("rate_limit=Some(\"100/60s\")"));
+    }
+
+    #[test]
+    fn parse_multiple_binds() {
+        let args = Args::try_parse_from(["tomoru"]).unwrap();
+        assert_eq!(args.bind, ["0.0.0.0:3000"]);
+
+        let args = Args::try_parse_from([
+            "tomoru",
+            "--bind",
+            "10.0.0.1:3000",
+            "--bind",
+            "127.0.0.1:3000,[::1]:3000",
+        ])
+        .unwrap();
+        assert_eq!(args.bind, ["10.0.0.1:3000", "127.0.0.1:3000", "[::1]:3000"]);
+    }
+
+    #[tokio::test]
+    async fn serve_listeners_share_state() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let state = Arc::new(AppState::default());
+        let args = Args::try_parse_from(["tomoru"]).unwrap();
+        let app = build_router(&args, state.clone());
+
+        let mut listeners = Vec::new();
+        for _ in 0..2 {
+            let bind_addr = SocketAddr::from(([127, 0, 0, 1], 0));
+            listeners.push(bind_tcp(bind_addr, None).await.unwrap());
+        }
+        let addrs: Vec<SocketAddr> = listeners
+            .iter()
+            .map(|listener| listener.local_addr().unwrap())
+            .collect();
+        let server = tokio::spawn(serve_listeners(
+            app,
+            listeners,
+            None,
+            None,
+            TcpOptions::default(),
+            false,
+            state.shutdown.clone(),
+        ));
+
+        for addr in addrs {
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(b"GET /ping HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 200 OK"));
+        }
+
+        // Both listeners counted into the same state
+        assert_eq!(
+            state.get_sorted_ip_counts(),
+            vec![(IpAddr::V4(Ipv4Addr::LOCALHOST), 2)]
+        );
+
+        state.shutdown.cancel();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn serve_listeners_http2() {
+        use hyper_util::rt::{TokioExecutor, TokioIo};
+
+        async fn ping_h2(addr: SocketAddr) -> Result<Response, hyper::Error> {
+            let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let (mut sender, conn) =
+                hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                    .await?;
+            tokio::spawn(conn);
+            let request = Request::get("http://127.0.0.1/ping")
+                .body(Body::empty())
+                .unwrap();
+            let response = sender.send_request(request).await?;
+            Ok(response.map(Body::new))
+        }
+
+        for http2 in [true, false] {
+            let state = Arc::new(AppState::default());
+            let args = Args::try_parse_from(["tomoru"]).unwrap();
+            let app = build_router(&args, state.clone());
+
+            let bind_addr = SocketAddr::from(([127, 0, 0, 1], 0));
+            let listener = bind_tcp(bind_addr, None).await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = tokio::spawn(serve_listeners(
+                app,
+                vec![listener],
+                None,
+                None,
+                TcpOptions::default(),
+                http2,
+                state.shutdown.clone(),
+            ));
+
+            if http2 {
+                // h2c with prior knowledge is counted just like HTTP/1
+                let response = ping_h2(addr).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                assert_eq!(response.version(), axum::http::Version::HTTP_2);
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                assert_eq!(&body[..], b"pong");
+                assert_eq!(
+                    state.get_sorted_ip_counts(),
+                    vec![(IpAddr::V4(Ipv4Addr::LOCALHOST), 1)]
+                );
+            } else {
+                assert!(ping_h2(addr).await.is_err());
+                assert!(state.get_sorted_ip_counts().is_empty());
+            }
+
+            state.shutdown.cancel();
+            server.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn serve_tcp_upgrades_websockets() {
+        use futures_util::StreamExt;
+
+        let state = Arc::new(AppState::default());
+        let args = Args::try_parse_from(["tomoru"]).unwrap();
+        let app = build_router(&args, state.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve_tcp(
+            app,
+            listener,
+            None,
+            None,
+            TcpOptions::default(),
+            false,
+            state.shutdown.clone(),
+        ));
+
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/stats/ws", addr))
+            .await
+            .unwrap();
+        let message = time::timeout(Duration::from_secs(5), socket.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(message.is_text());
+
+        state.shutdown.cancel();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn h2c_preface_split_across_reads() {
+        use tokio::io::AsyncReadExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = TcpAcceptor {
+            limiter: None,
+            options: TcpOptions::default(),
+            reject_h2c: true,
+        };
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = acceptor.limit(stream).unwrap();
+        let mut buf = [0; 64];
+
+        client.write_all(&H2C_PREFACE[..10]).await.unwrap();
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 10);
+        client.write_all(&H2C_PREFACE[10..]).await.unwrap();
+        let error = stream.read(&mut buf).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // Anything else passes through untouched
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = acceptor.limit(stream).unwrap();
+        client.write_all(b"PRI / HTTP/1.1\r\n").await.unwrap();
+        client.write_all(&H2C_PREFACE[14..]).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut read = Vec::new();
+        stream.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read.len(), 16 + H2C_PREFACE.len() - 14);
+    }
+
+    #[tokio::test]
+    async fn tcp_options_apply() {
+        let args =
+            Args::try_parse_from(["tomoru", "--tcp-nodelay", "--tcp-keepalive", "30"]).unwrap();
+        let options = TcpOptions::from_args(&args);
+        assert_eq!(
+            options,
+            TcpOptions {
+                nodelay: true,
+                keepalive: Some(Duration::from_secs(30)),
+            }
+        );
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        assert!(!stream.nodelay().unwrap());
+        assert!(!socket2::SockRef::from(&stream).keepalive().unwrap());
+
+        options.apply(&stream).unwrap();
+        assert!(stream.nodelay().unwrap());
+        assert!(socket2::SockRef::from(&stream).keepalive().unwrap());
+
+        assert!(Args::try_parse_from(["tomoru", "--tcp-keepalive", "0"]).is_err());
+    }
+
+    #[test]
+    fn connection_limiter() {
+        let limiter = Arc::new(ConnectionLimiter::new(2));
+        let ip1 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let ip2 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+        let first = limiter.acquire(ip1).unwrap();
+        let second = limiter.acquire(ip1).unwrap();
+        assert!(limiter.acquire(ip1).is_none());
+
+        // Other IPs have their own limit
+        let other = limiter.acquire(ip2).unwrap();
+
+        // Closing a connection frees its slot
+        drop(first);
+        let third = limiter.acquire(ip1).unwrap();
+        assert!(limiter.acquire(ip1).is_none());
+
+        drop((second, third, other));
+        assert!(limiter.active.is_empty());
+    }
+
+    #[tokio::test]
+    async fn max_conns_per_ip_closes_excess_connections() {
+        use tokio::io::AsyncReadExt;
+
+        let state = Arc::new(AppState::default());
+        let args = Args::try_parse_from(["tomoru"]).unwrap();
+        let app = build_router(&args, state.clone());
+        let listener = bind_tcp(SocketAddr::from(([127, 0, 0, 1], 0)), None)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let limiter = Arc::new(ConnectionLimiter::new(1));
+        let server = tokio::spawn(serve_listeners(
+            app,
+            vec![listener],
+            None,
+            Some(limiter.clone()),
+            TcpOptions::default(),
+            false,
+            state.shutdown.clone(),
+        ));
+
+        // Wait for the acceptor to register a connection's slot, or to release it
+        let settle = |open: bool| {
+            let limiter = limiter.clone();
+            time::timeout(Duration::from_secs(5), async move {
+                while limiter.active.is_empty() == open {
+                    time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+        };
+
+        // The first connection stays open without sending a request
+        let idle = tokio::net::TcpStream::connect(addr).await.unwrap();
+        settle(true).await.unwrap();
+        let mut excess = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut response = Vec::new();
+        excess.read_to_end(&mut response).await.unwrap();
+        assert!(response.is_empty());
+
+        // Once it's closed, the next connection is served
+        drop(idle);
+        settle(false).await.unwrap();
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /ping HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+
+        state.shutdown.cancel();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn admin_bind_splits_stats_routes() {
+        let state = Arc::new(AppState::default());
+        let args = ["--admin-bind", "127.0.0.1:9000", "--enable-reset"];
+        let public = test_router(&args, state.clone());
+        let args = Args::try_parse_from(std::iter::once("tomoru").chain(args)).unwrap();
+        let admin = build_admin_router(&args, state.clone())
+            .layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000))));
+        let status = |app: Router, request: Request| async move {
+            app.oneshot(request).await.unwrap().status()
+        };
+        let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+        let post = |uri: &str| Request::post(uri).body(Body::empty()).unwrap();
+
+        // The public router only serves /ping
+        assert_eq!(status(public.clone(), get("/ping")).await, StatusCode::OK);
+        for request in [
+            get("/stats"),
+            get("/stats.json"),
+            get("/metrics"),
+            post("/reset"),
+        ] {
+            assert_eq!(status(public.clone(), request).await, StatusCode::NOT_FOUND);
+        }
+
+        // While the admin router serves the rest of the same state, uncounted
+        assert_eq!(state.total_requests(), 5);
+        assert_eq!(
+            status(admin.clone(), get("/ping")).await,
+            StatusCode::NOT_FOUND
+        );
+        let response = admin.clone().oneshot(get("/stats")).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "IPs:\n  127.0.0.1: 5\n");
+        assert_eq!(status(admin.clone(), get("/healthz")).await, StatusCode::OK);
+        assert_eq!(status(admin, post("/reset")).await, StatusCode::OK);
+        assert_eq!(state.total_requests(), 0);
+    }
+
+    #[tokio::test]
+    async fn api_error_responses() {
+        for (error, status, body) in [
+            (
+                ApiError::BadRequest("Invalid IP address"),
+                StatusCode::BAD_REQUEST,
+                serde_json::json!({"error": "Invalid IP address", "code": 400}),
+            ),
+            (
+                ApiError::Unauthorized,
+                StatusCode::UNAUTHORIZED,
+                serde_json::json!({"error": "Unauthorized", "code": 401}),
+            ),
+            (
+                ApiError::Forbidden,
+                StatusCode::FORBIDDEN,
+                serde_json::json!({"error": "Forbidden", "code": 403}),
+            ),
+            (
+                ApiError::NotFound("IP address not seen"),
+                StatusCode::NOT_FOUND,
+                serde_json::json!({"error": "IP address not seen", "code": 404}),
+            ),
+            (
+                ApiError::TooManyRequests,
+                StatusCode::TOO_MANY_REQUESTS,
+                serde_json::json!({"error": "Too many requests", "code": 429}),
+            ),
+            (
+                ApiError::Internal,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                serde_json::json!({"error": "Internal server error", "code": 500}),
+            ),
+        ] {
+            let response = error.into_response();
+            assert_eq!(response.status(), status);
+            assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+            let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(json, body);
+        }
+    }
+
+    #[tokio::test]
+    async fn rate_limited_error_body() {
+        let state = Arc::new(AppState {
+            rate_limiter: Some(RateLimiter::new(1, Duration::from_secs(60))),
+            ..Default::default()
+        });
+        let app = test_router(&[], state);
+
+        app.clone()
+            .oneshot(Request::get("/ping").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let response = app
+            .oneshot(Request::get("/ping").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"error": "Too many requests", "code": 429})
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_path_is_counted() {
+        let state = Arc::new(AppState::default());
+        let app = test_router(&[], state.clone());
+
+        let response = app
+            .oneshot(Request::get("/wp-login.php").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json, serde_json::json!({"error": "Not found", "code": 404}));
+
+        assert_eq!(
+            state.get_sorted_ip_counts(),
+            vec![(IpAddr::V4(Ipv4Addr::LOCALHOST), 1)]
+        );
+        assert_eq!(state.status_counts.get(&404), Some(1));
+    }
+
+    #[tokio::test]
+    async fn cors_on_stats_routes_only() {
+        let origin = "https://dashboard.example.com";
+        let request = |uri: &str| {
+            Request::get(uri)
+                .header(header::ORIGIN, origin)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let app = test_router(&[], Arc::new(AppState::default()));
+        let response = app.oneshot(request("/stats.json")).await.unwrap();
+        assert!(!response
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        let app = test_router(&["--cors-origin", origin], Arc::new(AppState::default()));
+        let response = app.clone().oneshot(request("/stats.json")).await.unwrap();
+        assert_eq!(
+            response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            origin
+        );
+        let response = app.oneshot(request("/ping")).await.unwrap();
+        assert!(!response
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        let app = test_router(&["--cors-origin", "*"], Arc::new(AppState::default()));
+        let response = app.oneshot(request("/stats")).await.unwrap();
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+    }
+
+    #[tokio::test]
+    async fn stats_compressed() {
+        let state = Arc::new(AppState::default());
+        for i in 0..100 {
+            state.increment_ip_count(IpAddr::V4(Ipv4Addr::new(10, 0, 0, i)));
+        }
+        let app = test_router(&[], state);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::get("/stats.json")
+                    .header(header::ACCEPT_ENCODING, "gzip")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+
+        let response = app
+            .oneshot(
+                Request::get("/ping")
+                    .header(header::ACCEPT_ENCODING, "gzip")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+    }
+
+    #[test]
+    fn advance_history() {
+        let state = AppState {
+            history_minutes: 2,
+            ..Default::default()
+        };
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let start = 1_700_000_040;
+
+        state.increment_ip_count(ip);
+        state.advance_history(start);
+        state.increment_ip_count(ip);
+        state.advance_history(start + 30);
+        assert_eq!(
+            state.get_timeseries(),
+            vec![MinuteCount {
+                minute: start,
+                count: 2
+            }]
+        );
+
+        state.increment_ip_count(ip);
+        state.increment_ip_count(ip);
+        state.increment_ip_count(ip);
+        state.advance_history(start + 60);
+        assert_eq!(
+            state.get_timeseries(),
+            vec![
+                MinuteCount {
+                    minute: start,
+                    count: 2
+                },
+                MinuteCount {
+                    minute: start + 60,
+                    count: 3
+                },
+            ]
+        );
+
+        // Only the last 2 minutes are kept
+        state.advance_history(start + 120);
+        assert_eq!(
+            state.get_timeseries(),
+            vec![
+                MinuteCount {
+                    minute: start + 60,
+                    count: 3
+                },
+                MinuteCount {
+                    minute: start + 120,
+                    count: 0
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn history_minutes_range() {
+        let parse = |minutes: &str| Args::try_parse_from(["tomoru", "--history-minutes", minutes]);
+        assert!(parse("0").is_err());
+        assert!(parse(&(MAX_HISTORY_MINUTES + 1).to_string()).is_err());
+        assert_eq!(
+            parse(&MAX_HISTORY_MINUTES.to_string())
+                .unwrap()
+                .history_minutes,
+            MAX_HISTORY_MINUTES
+        );
+    }
+
+    #[test]
+    fn expected_ips_range() {
+        let parse = |ips: &str| Args::try_parse_from(["tomoru", "--expected-ips", ips]);
+        assert!(parse("18446744073709551615").is_err());
+        assert!(parse(&(MAX_EXPECTED_IPS + 1).to_string()).is_err());
+        assert_eq!(parse("1000000").unwrap().expected_ips, Some(1_000_000));
+    }
+
+    #[test]
+    fn with_capacity_preallocates() {
+        let state = AppState::with_capacity(1600);
+        for index in 0..SHARD_COUNT {
+            assert!(state.ip_counts.read_shard(index).capacity() >= 100);
+        }
+        assert!(state.rates.capacity() >= 1600);
+
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        state.increment_ip_count(ip);
+        assert_eq!(state.get_sorted_ip_counts(), vec![(ip, 1)]);
+    }
+
+    #[tokio::test]
+    async fn in_flight_requests() {
+        let args = Args::try_parse_from(["tomoru"]).unwrap();
+        let state = Arc::new(AppState::default());
+        let (entered_tx, mut entered_rx) = tokio::sync::mpsc::unbounded_channel();
+        let release = Arc::new(tokio::sync::Notify::new());
+        let routes = Router::new().route(
+            "/slow",
+            get({
+                let release = release.clone();
+                move || async move {
+                    entered_tx.send(()).unwrap();
+                    release.notified().await;
+                }
+            }),
+        );
+        let app = wrap_routes(routes, &args, state.clone())
+            .layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000))));
+        let in_flight = || state.in_flight.load(Ordering::Relaxed);
+
+        let first = tokio::spawn(
+            app.clone()
+                .oneshot(Request::get("/slow").body(Body::empty()).unwrap()),
+        );
+        entered_rx.recv().await.unwrap();
+        let second = tokio::spawn(
+            app.clone()
+                .oneshot(Request::get("/slow").body(Body::empty()).unwrap()),
+        );
+        entered_rx.recv().await.unwrap();
+        assert_eq!(in_flight(), 2);
+
+        release.notify_one();
+        let finished = first.await.unwrap().unwrap();
+        assert_eq!(finished.status(), StatusCode::OK);
+        assert_eq!(in_flight(), 1);
+
+        // A request dropped mid-flight, e.g. by a closed connection, is no longer in flight
+        second.abort();
+        assert!(second.await.unwrap_err().is_cancelled());
+        assert_eq!(in_flight(), 0);
+
+        // Uncounted routes aren't in flight either
+        app.oneshot(Request::get("/healthz").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(in_flight(), 0);
+
+        // Requests from ignored IPs are in flight without being counted
+        let args = Args::try_parse_from(["tomoru", "--ignore-ip", "127.0.0.1"]).unwrap();
+        let state = Arc::new(AppState {
+            ignored_ips: args.ignore_ips.iter().copied().collect(),
+            ..Default::default()
+        });
+        let routes = Router::new().route(
+            "/slow",
+            get({
+                let state = state.clone();
+                move || async move { state.in_flight.load(Ordering::Relaxed).to_string() }
+            }),
+        );
+        let app = wrap_routes(routes, &args, state.clone())
+            .layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000))));
+        let response = app
+            .oneshot(Request::get("/slow").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "1");
+        assert_eq!(state.ip_counts.len(), 0);
+    }
+
+    #[test]
+    fn log_subscriber_formats() {
+        let log_with = |format| {
+            let logs = LogBuffer::default();
+            let subscriber = log_subscriber(format, EnvFilter::new("info"), {
+                let logs = logs.clone();
+                move || logs.clone()
+            });
+            tracing::subscriber::with_default(subscriber, || {
+                log_stats(&AppState::default());
+            });
+            logs.contents()
+        };
+
+        let text = log_with(LogFormat::Text);
+        assert!(text.contains("Request stats"));
+        assert!(serde_json::from_str::<serde_json::Value>(text.trim()).is_err());
+
+        let json = log_with(LogFormat::Json);
+        let line: serde_json::Value = serde_json::from_str(json.trim()).unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["fields"]["unique_ips"], 0);
+        assert!(line["fields"]["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("Request stats"));
+    }
+
+    #[test]
+    fn has_bearer_token() {
+        let with_authorization = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::AUTHORIZATION, value.parse().unwrap());
+            headers
+        };
+
+        assert!(super::has_bearer_token(
+            &with_authorization("Bearer secret"),
+            "secret"
+        ));
+        assert!(!super::has_bearer_token(&HeaderMap::new(), "secret"));
+        assert!(!super::has_bearer_token(
+            &with_authorization("Bearer wrong!"),
+            "secret"
+        ));
+        assert!(!super::has_bearer_token(
+            &with_authorization("Bearer secre"),
+            "secret"
+        ));
+        assert!(!super::has_bearer_token(
+            &with_authorization("Basic secret"),
+            "secret"
+        ));
+    }
+
+    #[tokio::test]
+    async fn shutdown_endpoint() {
+        let request = |token: Option<&str>| {
+            let mut request = Request::post("/shutdown");
+            if let Some(token) = token {
+                request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+            }
+            request.body(Body::empty()).unwrap()
+        };
+
+        // Disabled without an admin token
+        let state = Arc::new(AppState::default());
+        let response = test_router(&[], state.clone())
+            .oneshot(request(Some("secret")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(!state.shutdown.is_cancelled());
+
+        let app = test_router(&["--admin-token", "secret"], state.clone());
+        let response = app.clone().oneshot(request(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = app.clone().oneshot(request(Some("guess"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(!state.shutdown.is_cancelled());
+
+        let response = app.oneshot(request(Some("secret"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert!(state.shutdown.is_cancelled());
+        assert!(state.is_draining());
+    }
+
+    #[tokio::test]
+    async fn stats_basic_auth() {
+        let app = test_router(
+            &["--stats-user", "admin", "--stats-pass", "hunter2"],
+            Arc::new(AppState::default()),
+        );
+        let request = |uri: &str, credentials: Option<&str>| {
+            let mut request = Request::get(uri);
+            if let Some(credentials) = credentials {
+                request = request.header(
+                    header::AUTHORIZATION,
+                    format!("Basic {}", BASE64.encode(credentials)),
+                );
+            }
+            request.body(Body::empty()).unwrap()
+        };
+
+        let response = app.clone().oneshot(request("/stats", None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers()[header::WWW_AUTHENTICATE],
+            "Basic realm=\"tomoru\""
+        );
+        for wrong in ["admin:hunter3", "root:hunter2", "admin:"] {
+            let response = app
+                .clone()
+                .oneshot(request("/stats.json", Some(wrong)))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        let response = app
+            .clone()
+            .oneshot(request("/metrics", Some("admin:hunter2")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // /ping stays open
+        let response = app.oneshot(request("/ping", None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn reset_requires_basic_auth() {
+        let state = Arc::new(AppState::default());
+        state.increment_ip_count(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        let app = test_router(
+            &[
+                "--stats-user",
+                "admin",
+                "--stats-pass",
+                "hunter2",
+                "--enable-reset",
+            ],
+            state.clone(),
+        );
+        let request = |uri: &str, credentials: Option<&str>| {
+            let mut request = Request::post(uri);
+            if let Some(credentials) = credentials {
+                request = request.header(
+                    header::AUTHORIZATION,
+                    format!("Basic {}", BASE64.encode(credentials)),
+                );
+            }
+            request.body(Body::empty()).unwrap()
+        };
+
+        for uri in ["/reset", "/stats/reset-ip/10.0.0.1"] {
+            let response = app.clone().oneshot(request(uri, None)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{}", uri);
+        }
+        assert_eq!(
+            state.count_for(&IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))),
+            Some(1)
+        );
+
+        let response = app
+            .oneshot(request("/stats/reset-ip/10.0.0.1", Some("admin:hunter2")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            state.count_for(&IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))),
+            None
+        );
+    }
+
+    #[test]
+    fn stats_auth_needs_both_credentials() {
+        assert!(Args::try_parse_from(["tomoru", "--stats-user", "admin"]).is_err());
+        assert!(Args::try_parse_from(["tomoru", "--stats-pass", "hunter2"]).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn supervise_restarts_after_panic() {
+        let shutdown = CancellationToken::new();
+        let (started_tx, mut started_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut runs = 0;
+        let supervisor = tokio::spawn(supervise("Test task", shutdown.clone(), move |shutdown| {
+            runs += 1;
+            let run = runs;
+            let started_tx = started_tx.clone();
+            async move {
+                started_tx.send(run).unwrap();
+                if run == 1 {
+                    panic!("transient failure");
+                }
+                shutdown.cancelled().await;
+            }
+        }));
+
+        assert_eq!(started_rx.recv().await, Some(1));
+        // The failed task is started again after the delay instead of staying dead
+        assert_eq!(started_rx.recv().await, Some(2));
+        assert!(!supervisor.is_finished());
+
+        shutdown.cancel();
+        supervisor.await.unwrap();
+    }
+}